@@ -0,0 +1,622 @@
+//! Commands from the console, socket and web controls, and the tunable parameters
+
+use crate::protocol::Color;
+use crate::sprite::{Direction, Theme};
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Parameters that can be changed while running through `POST /config`
+#[derive(Clone)]
+pub struct Tunables {
+    // None follows the delays stored in the sprite
+    pub frame_duration_ms: Option<u64>,
+    pub speed: u16,
+    pub repeat: u32,
+    pub tint: Option<Color>,
+}
+
+impl Tunables {
+    // Apply a JSON object of changed values, rejecting the whole patch if any value is invalid
+    pub fn patched(&self, patch: &serde_json::Value) -> Result<Tunables, String> {
+        let patch = patch.as_object().ok_or("Expected a JSON object")?;
+        let mut tunables = self.clone();
+        for (key, value) in patch {
+            match key.as_str() {
+                "frame_duration_ms" => {
+                    tunables.frame_duration_ms = match value {
+                        serde_json::Value::Null => None,
+                        value => Some(ranged_parameter(key, value, 1, 10_000)?),
+                    }
+                }
+                "speed" => tunables.speed = ranged_parameter(key, value, 1, 1_000)? as u16,
+                "repeat" => tunables.repeat = ranged_parameter(key, value, 1, 100)? as u32,
+                "tint" => {
+                    tunables.tint = match value {
+                        serde_json::Value::Null => None,
+                        serde_json::Value::String(color) => Some(color.parse()?),
+                        _ => return Err("tint must be a RRGGBB string or null".to_string()),
+                    }
+                }
+                _ => return Err(format!("Unknown parameter '{}'", key)),
+            }
+        }
+        Ok(tunables)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "frame_duration_ms": self.frame_duration_ms,
+            "speed": self.speed,
+            "repeat": self.repeat,
+            "tint": self.tint.map(|tint| tint.to_string()),
+        })
+    }
+}
+
+fn ranged_parameter(
+    key: &str,
+    value: &serde_json::Value,
+    min: u64,
+    max: u64,
+) -> Result<u64, String> {
+    value
+        .as_u64()
+        .filter(|value| (min..=max).contains(value))
+        .ok_or_else(|| format!("{} must be an integer from {} to {}", key, min, max))
+}
+
+/// Control input that is forwarded from the input threads to the main loop
+pub enum Command {
+    Move(Direction),
+    // Show the frames for a direction while still moving the way Pac-Man is heading
+    Face(Direction),
+    // Keep steering towards a canvas position, e.g. the pointer on the web page
+    Follow(u16, u16),
+    // Put Pac-Man at a canvas position right away
+    Goto(u16, u16),
+    Theme(&'static Theme),
+    Die,
+    // Hold Pac-Man still on the current frame, or let him go again
+    Pause,
+    // Erase Pac-Man from the canvas and exit
+    Quit,
+}
+
+/// What a socket connection that plays its own Pac-Man does, tagged with its player number
+pub enum PlayerEvent {
+    Joined(usize),
+    Command(usize, Command),
+    Left(usize),
+}
+
+/// Seats for `--max-players`, shared by the threads of the socket connections
+#[derive(Clone)]
+pub struct PlayerSeats {
+    max: usize,
+    taken: Arc<Mutex<usize>>,
+}
+
+impl PlayerSeats {
+    pub fn new(max: usize) -> Self {
+        PlayerSeats {
+            max,
+            taken: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    // Take a seat for a new player, false when all are taken
+    pub fn take(&self) -> bool {
+        let mut taken = self.taken.lock().unwrap();
+        let free = *taken < self.max;
+        if free {
+            *taken += 1;
+        }
+        free
+    }
+
+    pub fn release(&self) {
+        let mut taken = self.taken.lock().unwrap();
+        *taken = taken.saturating_sub(1);
+    }
+}
+
+/// Which command is discarded when the control queue is full
+#[derive(Copy, Clone, ValueEnum)]
+pub enum DropPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+/// Bounded queue of commands from the input threads to the main loop. A flood of input can't
+/// grow it, only the commands that fit are kept.
+#[derive(Clone)]
+pub struct CommandQueue {
+    commands: Arc<Mutex<VecDeque<Command>>>,
+    depth: usize,
+    policy: DropPolicy,
+}
+
+impl CommandQueue {
+    pub fn new(depth: usize, policy: DropPolicy) -> Self {
+        CommandQueue {
+            commands: Arc::new(Mutex::new(VecDeque::with_capacity(depth))),
+            depth,
+            policy,
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        let mut commands = self.commands.lock().unwrap();
+        if commands.len() >= self.depth {
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    commands.pop_front();
+                }
+                DropPolicy::DropNewest => return,
+            }
+        }
+        commands.push_back(command);
+    }
+
+    pub fn try_recv(&self) -> Option<Command> {
+        self.commands.lock().unwrap().pop_front()
+    }
+}
+
+// Parse a line based control command as sent over the control socket and FIFO, two direction
+// keys like "wa" move diagonally
+pub fn parse_command(text: &str) -> Option<Command> {
+    let direction = |key| match key {
+        'w' => Some(Direction::Up),
+        'a' => Some(Direction::Left),
+        's' => Some(Direction::Down),
+        'd' => Some(Direction::Right),
+        _ => None,
+    };
+    let text = text.trim();
+    let mut words = text.split_whitespace();
+    if words
+        .next()
+        .is_some_and(|word| word.eq_ignore_ascii_case("GOTO"))
+    {
+        return match (words.next(), words.next(), words.next()) {
+            (Some(x), Some(y), None) => Some(Command::Goto(x.parse().ok()?, y.parse().ok()?)),
+            _ => None,
+        };
+    }
+    match text {
+        "x" => Some(Command::Die),
+        "q" => Some(Command::Quit),
+        "p" => Some(Command::Pause),
+        keys => {
+            // An `f` in front of the keys turns Pac-Man without steering
+            let (keys, command): (_, fn(Direction) -> Command) = match keys.strip_prefix('f') {
+                Some(keys) => (keys, Command::Face),
+                None => (keys, Command::Move),
+            };
+            let mut keys = keys.chars();
+            match (keys.next(), keys.next(), keys.next()) {
+                (Some(key), None, None) => direction(key).map(command),
+                (Some(first), Some(second), None) => {
+                    direction(first)?.combined(direction(second)?).map(command)
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+// How soon after a direction key a perpendicular one combines with it into a diagonal
+const DIAGONAL_KEY_WINDOW: std::time::Duration = std::time::Duration::from_millis(150);
+
+/// Pairs direction keys pressed on the console into diagonals, the terminal only reports one
+/// key at a time
+#[derive(Default)]
+pub struct DiagonalKeys {
+    last: Option<(Direction, std::time::Instant)>,
+}
+
+impl DiagonalKeys {
+    // The direction to move in for a key, the diagonal with the previous key if that was a
+    // perpendicular one pressed just before
+    pub fn press(&mut self, direction: Direction, now: std::time::Instant) -> Direction {
+        let diagonal = self
+            .last
+            .filter(|&(_, at)| now.saturating_duration_since(at) < DIAGONAL_KEY_WINDOW)
+            .and_then(|(last, _)| last.combined(direction));
+        self.last = Some((direction, now));
+        diagonal.unwrap_or(direction)
+    }
+}
+
+// Consecutive errors after which an input thread gives up
+const MAX_CONSECUTIVE_INPUT_ERRORS: u32 = 50;
+
+/// Keeps an input loop from spinning when its source keeps failing
+#[derive(Default)]
+pub struct ErrorBackoff {
+    consecutive_errors: u32,
+}
+
+impl ErrorBackoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn succeeded(&mut self) {
+        self.consecutive_errors = 0;
+    }
+
+    // Log and sleep a little longer after each error in a row, false once it is time to give up
+    pub fn failed(&mut self, source: &str, error: impl std::fmt::Display) -> bool {
+        self.consecutive_errors += 1;
+        if self.consecutive_errors >= MAX_CONSECUTIVE_INPUT_ERRORS {
+            eprintln!(
+                "{} failed {} times in a row, giving up: {}",
+                source, self.consecutive_errors, error
+            );
+            return false;
+        }
+        eprintln!("{} failed, retrying: {}", source, error);
+        let backoff_ms = 10u64 << self.consecutive_errors.min(6);
+        std::thread::sleep(std::time::Duration::from_millis(backoff_ms));
+        true
+    }
+}
+
+// Create the control FIFO unless a FIFO already exists at the path
+#[cfg(unix)]
+pub fn create_fifo(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_fifo() => return Ok(()),
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists but is not a FIFO",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: path is a valid, NUL terminated C string that outlives the call
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Name of a control server and whether it started, sent by its thread once it tried
+pub type BindOutcome = (String, Result<(), String>);
+
+// How long the startup summary waits for each control server
+pub const BIND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// Start a control server and report the outcome under its name, None if it failed to start
+pub fn bind_control<T, E: std::fmt::Display>(
+    name: &str,
+    outcomes: &Sender<BindOutcome>,
+    bind: impl FnOnce() -> Result<T, E>,
+) -> Option<T> {
+    let bound = bind();
+    let outcome = bound.as_ref().map(|_| ()).map_err(|e| e.to_string());
+    outcomes.send((name.to_string(), outcome)).ok();
+    bound.ok()
+}
+
+// Wait for the outcomes of the given number of control servers, report those that failed and
+// return the names of those that started. Servers that don't report in time are left out.
+pub fn started_controls(
+    outcomes: &Receiver<BindOutcome>,
+    servers: usize,
+    timeout: std::time::Duration,
+) -> Vec<String> {
+    let mut started = Vec::new();
+    for _ in 0..servers {
+        match outcomes.recv_timeout(timeout) {
+            Ok((name, Ok(()))) => started.push(name),
+            Ok((name, Err(e))) => eprintln!("Control by {} is unavailable: {}", name, e),
+            Err(_) => break,
+        }
+    }
+    started
+}
+
+// Forward the commands written to the control FIFO. Opening blocks until a writer shows up, and
+// the FIFO is reopened whenever the last writer closes it. Only returns if it can't be opened.
+#[cfg(unix)]
+pub fn read_control_fifo(path: &std::path::Path, queue: &CommandQueue) {
+    loop {
+        let fifo = match std::fs::File::open(path) {
+            Ok(fifo) => fifo,
+            Err(e) => {
+                eprintln!("Failed to open control FIFO {}: {}", path.display(), e);
+                return;
+            }
+        };
+        for line in io::BufReader::new(fifo).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Some(command) = parse_command(&line) {
+                queue.send(command);
+            }
+        }
+    }
+}
+
+// Set once SIGINT or SIGTERM arrived while they are caught
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Turn SIGINT and SIGTERM into a request to stop that the main loop polls with
+/// [`stop_requested`], so it can shut down cleanly instead of being killed
+#[cfg(unix)]
+pub fn catch_stop_signals() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(
+                signal,
+                request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Largest request body accepted by `POST /config`
+pub const MAX_CONFIG_BODY_BYTES: u64 = 64 * 1024;
+
+// Largest control page that is served from --web-root
+const MAX_WEB_PAGE_BYTES: u64 = 1024 * 1024;
+
+// How long a rendered `GET /preview.png` is reused for rapid refreshes
+pub const PREVIEW_CACHE_DURATION: std::time::Duration = std::time::Duration::from_millis(500);
+
+// The control page from the web root, or the built-in page if there is none or it can't be used
+pub fn load_control_page(web_root: Option<&std::path::Path>) -> Cow<'static, str> {
+    let embedded = Cow::Borrowed(include_str!("index.html"));
+    let Some(web_root) = web_root else {
+        return embedded;
+    };
+    let path = web_root.join("index.html");
+    let page = std::fs::File::open(&path).and_then(|file| {
+        if file.metadata()?.len() > MAX_WEB_PAGE_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the page is larger than {} bytes", MAX_WEB_PAGE_BYTES),
+            ));
+        }
+        let mut page = String::new();
+        file.take(MAX_WEB_PAGE_BYTES).read_to_string(&mut page)?;
+        Ok(page)
+    });
+    match page {
+        Ok(page) => Cow::Owned(page),
+        Err(e) => {
+            eprintln!(
+                "Failed to read {}, serving the built-in control page: {}",
+                path.display(),
+                e
+            );
+            embedded
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // The next command the queue receives within a second
+    fn next_command(queue: &CommandQueue) -> Option<Command> {
+        let started = std::time::Instant::now();
+        while started.elapsed() < std::time::Duration::from_secs(1) {
+            if let Some(command) = queue.try_recv() {
+                return Some(command);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        None
+    }
+
+    #[test]
+    fn keys_pressed_together_make_a_diagonal() {
+        let start = std::time::Instant::now();
+        let at = |ms| start + std::time::Duration::from_millis(ms);
+        let mut keys = DiagonalKeys::default();
+        assert!(keys.press(Direction::Up, at(0)) == Direction::Up);
+        assert!(keys.press(Direction::Left, at(100)) == Direction::UpLeft);
+        // Too late to combine with the previous key
+        assert!(keys.press(Direction::Down, at(300)) == Direction::Down);
+        // Opposite directions don't combine
+        assert!(keys.press(Direction::Up, at(350)) == Direction::Up);
+        assert!(keys.press(Direction::Right, at(400)) == Direction::UpRight);
+    }
+
+    #[test]
+    fn two_direction_keys_make_a_diagonal_command() {
+        for (text, expected) in [
+            ("wa", Direction::UpLeft),
+            ("aw", Direction::UpLeft),
+            ("wd", Direction::UpRight),
+            ("sa", Direction::DownLeft),
+            ("ds", Direction::DownRight),
+        ] {
+            assert!(
+                matches!(parse_command(text), Some(Command::Move(direction)) if direction == expected),
+                "{}",
+                text
+            );
+        }
+        assert!(matches!(
+            parse_command("fsd"),
+            Some(Command::Face(Direction::DownRight))
+        ));
+        for text in ["ws", "ad", "ww", "wad"] {
+            assert!(parse_command(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn players_are_turned_away_once_all_seats_are_taken() {
+        let seats = PlayerSeats::new(2);
+        let other_thread = seats.clone();
+        assert!(seats.take());
+        assert!(other_thread.take());
+        assert!(!seats.take());
+        // A player leaving frees a seat for the next one
+        other_thread.release();
+        assert!(seats.take());
+        assert!(!other_thread.take());
+    }
+
+    fn tunables() -> Tunables {
+        Tunables {
+            frame_duration_ms: Some(100),
+            speed: 2,
+            repeat: 1,
+            tint: None,
+        }
+    }
+
+    #[test]
+    fn partial_patches_change_only_their_parameters() {
+        let patch = serde_json::json!({"speed": 5, "tint": "ff8000", "frame_duration_ms": null});
+        let patched = tunables().patched(&patch).unwrap();
+        assert_eq!(
+            patched.to_json(),
+            serde_json::json!({
+                "frame_duration_ms": null,
+                "speed": 5,
+                "repeat": 1,
+                "tint": "ff8000",
+            })
+        );
+    }
+
+    #[test]
+    fn one_invalid_value_rejects_the_whole_patch() {
+        let original = tunables();
+        for patch in [
+            serde_json::json!({"speed": 5, "frame_duration_ms": 0}),
+            serde_json::json!({"speed": 5, "frame_duration_ms": 10_001}),
+            serde_json::json!({"repeat": 3, "speed": 1_001}),
+            serde_json::json!({"speed": -1}),
+            serde_json::json!({"speed": 5, "turbo": true}),
+            serde_json::json!({"speed": "fast"}),
+            serde_json::json!({"speed": 2.5}),
+            serde_json::json!({"tint": 255}),
+            serde_json::json!({"tint": "orange"}),
+            serde_json::json!(["speed", 5]),
+        ] {
+            assert!(original.patched(&patch).is_err(), "{}", patch);
+        }
+        assert_eq!(original.to_json(), tunables().to_json());
+    }
+
+    // Flood the queue with numbered commands from several threads at once, then drain it
+    fn flooded(depth: usize, policy: DropPolicy) -> Vec<u16> {
+        let queue = CommandQueue::new(depth, policy);
+        let senders: Vec<_> = (0..4)
+            .map(|thread| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    for i in 0..1000 {
+                        queue.send(Command::Goto(thread * 1000 + i, 0));
+                    }
+                })
+            })
+            .collect();
+        senders
+            .into_iter()
+            .for_each(|sender| sender.join().unwrap());
+        // Sent last, after every thread is done
+        queue.send(Command::Goto(u16::MAX, 0));
+        std::iter::from_fn(|| queue.try_recv())
+            .map(|command| match command {
+                Command::Goto(x, _) => x,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_flooded_queue_keeps_the_newest_commands_when_dropping_the_oldest() {
+        let kept = flooded(16, DropPolicy::DropOldest);
+        assert_eq!(kept.len(), 16);
+        assert_eq!(kept.last(), Some(&u16::MAX));
+    }
+
+    #[test]
+    fn a_flooded_queue_keeps_the_first_commands_when_dropping_the_newest() {
+        let kept = flooded(16, DropPolicy::DropNewest);
+        assert_eq!(kept.len(), 16);
+        assert!(!kept.contains(&u16::MAX));
+        // Each thread's commands stay in the order it sent them
+        for thread in 0..4 {
+            let sent: Vec<_> = kept.iter().filter(|&&x| x / 1000 == thread).collect();
+            assert!(sent.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn controls_on_taken_ports_are_reported_unavailable() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_addr = taken.local_addr().unwrap();
+        let (outcomes, outcomes_rx) = std::sync::mpsc::channel();
+        let bind = || std::net::TcpListener::bind(taken_addr);
+        assert!(bind_control("socket on a taken port", &outcomes, bind).is_none());
+        let bind = || std::net::TcpListener::bind("127.0.0.1:0");
+        assert!(bind_control("socket on a free port", &outcomes, bind).is_some());
+        // A third server never reports, the summary still comes once it timed out
+        let timeout = std::time::Duration::from_millis(50);
+        assert_eq!(
+            started_controls(&outcomes_rx, 3, timeout),
+            ["socket on a free port"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifo_commands_are_forwarded_across_writers() {
+        let path = std::env::temp_dir().join(format!("pixelflut-pac-{}.fifo", std::process::id()));
+        create_fifo(&path).unwrap();
+        // An existing FIFO is reused
+        create_fifo(&path).unwrap();
+        let queue = CommandQueue::new(8, DropPolicy::DropOldest);
+        let reader_queue = queue.clone();
+        let reader_path = path.clone();
+        std::thread::spawn(move || read_control_fifo(&reader_path, &reader_queue));
+
+        writeln!(std::fs::File::create(&path).unwrap(), "a").unwrap();
+        assert!(matches!(
+            next_command(&queue),
+            Some(Command::Move(Direction::Left))
+        ));
+        // The first writer is gone, a new one is still heard
+        writeln!(std::fs::File::create(&path).unwrap(), "nonsense\nfw").unwrap();
+        assert!(matches!(
+            next_command(&queue),
+            Some(Command::Face(Direction::Up))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+//! Draw an animated Pac-Man on a Pixelflut canvas.
+//!
+//! The `pixelflut_pac` binary wires the command line and the control threads to this library.
+
+pub mod control;
+pub mod motion;
+pub mod protocol;
+pub mod render;
+pub mod sprite;
+pub mod stats;
+pub mod transport;
+
+pub use protocol::{get_canvas_size, Color, Coordinates, Pixel};
+pub use render::{write_frame_to_stream, RenderOptions};
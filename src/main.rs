@@ -1,297 +1,1252 @@
-use clap::Parser;
-use console::Term;
-use image::codecs::gif::GifDecoder;
-use image::imageops::{flip_horizontal, flip_vertical, resize, rotate90};
-use image::{AnimationDecoder, Rgba};
+use clap::{Parser, ValueEnum};
+use console::{Key, Term};
+use pixelflut_pac::control::{
+    bind_control, catch_stop_signals, create_fifo, load_control_page, parse_command,
+    read_control_fifo, started_controls, stop_requested, BindOutcome, Command, CommandQueue,
+    DiagonalKeys, DropPolicy, ErrorBackoff, PlayerEvent, PlayerSeats, Tunables, BIND_TIMEOUT,
+    MAX_CONFIG_BODY_BYTES, PREVIEW_CACHE_DURATION,
+};
+use pixelflut_pac::motion::{
+    follow_move_command, pointer_target, step_towards, Boundary, Demo, DemoFeature, Facing,
+    Lifecycle, Orbit, Patrol, Players, SwarmMember,
+};
+use pixelflut_pac::protocol::{
+    connect_with_retry, detect_offset_support, detect_pixel_rate_limit, get_canvas_size,
+    read_pixel, Color, Coordinates, Pixel,
+};
+use pixelflut_pac::render::{
+    color_grouped_order, erase_last_frame, hilbert_order, read_canvas_colors, readback_reader,
+    readback_sample, redraw_altered_pixels, render_scene, turn_flash, write_frame_to_stream,
+    write_pixel, DirectionTrail, DrawnFrame, RenderOptions,
+};
+use pixelflut_pac::sprite::{
+    apply_color_options, centered, cleared_frame, death_frames, decode_sprite, fetch_sprite,
+    find_theme, fit_to_canvas, footprint, frame_index, load_theme, parse_theme, procedural_frames,
+    ColorDepth, ColorOptions, ColorRemap, Direction, FrameFit, FrameTimeline, FreezeAnimation,
+    Pulse, ResizeFilter, SpriteFrames, Theme, DEFAULT_FRAME_DURATION_MS, THEMES,
+};
+use pixelflut_pac::stats::{
+    calibrate_throughput, print_dry_run_stats, Metrics, MovementStats, ReadbackStats, Telemetry,
+    AUTO_OPTIMIZE_PASSES_PER_SECOND,
+};
+use pixelflut_pac::transport::{
+    create_recording, forward_relayed_pixels, open_recording, replay_recording, serve_relay,
+    BinaryPixels, ByteRateLimiter, ConnectionPool, CountingWriter, DeltaFilter, Framebuffer,
+    PixelRateLimiter, Reconnecting, Recorder, RelayedPixel, RetryOnWouldBlock, Tee, Wall,
+    WallRouter,
+};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::io::{self, BufRead, Cursor, Write};
+use std::io::{self, BufRead, Cursor, Read, Write};
 use std::net::TcpStream;
 use std::str::FromStr;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use tiny_http::{Header, Response, Server, StatusCode};
 
-#[derive(Copy, Clone)]
-struct Coordinates {
+/// An additional pixelflut server whose canvas starts at an offset of the shared canvas
+#[derive(Clone)]
+struct WallSpec {
+    address: String,
     x: u16,
     y: u16,
-    bounds: (u16, u16),
 }
 
-impl std::ops::Add<Coordinates> for Coordinates {
-    type Output = Coordinates;
+impl FromStr for WallSpec {
+    type Err = String;
 
-    fn add(self, other: Coordinates) -> Coordinates {
-        Coordinates {
-            x: (self.x + other.x + self.bounds.0) % self.bounds.0,
-            y: (self.y + other.y + self.bounds.1) % self.bounds.1,
-            bounds: self.bounds,
-        }
-    }
-}
-
-#[derive(Copy, Clone)]
-struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
-}
-
-impl From<Rgba<u8>> for Color {
-    fn from(rgba: Rgba<u8>) -> Self {
-        Color {
-            r: rgba[0],
-            g: rgba[1],
-            b: rgba[2],
-        }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid wall '{}': expected HOST:PORT@X,Y", s);
+        let (address, offset) = s.rsplit_once('@').ok_or_else(invalid)?;
+        let (x, y) = offset.split_once(',').ok_or_else(invalid)?;
+        Ok(WallSpec {
+            address: address.to_string(),
+            x: x.trim().parse().map_err(|_| invalid())?,
+            y: y.trim().parse().map_err(|_| invalid())?,
+        })
     }
 }
 
-struct Pixel {
-    point: Coordinates,
-    rgb: Color,
-}
+// Optional cargo features, listed by `--version --verbose` so bug reports include them
+const OPTIONAL_FEATURES: &[(&str, bool)] = &[
+    ("http-sprite", cfg!(feature = "http-sprite")),
+    ("framebuffer", cfg!(feature = "framebuffer")),
+];
 
-impl Pixel {
-    // Implement output function for buffer writing with a
-    fn write<T: Write>(&self, buffer: &mut T) -> io::Result<()> {
-        writeln!(
-            buffer,
-            "PX {x} {y} {r:02x}{g:02x}{b:02x}",
-            x = self.point.x,
-            y = self.point.y,
-            r = self.rgb.r,
-            g = self.rgb.g,
-            b = self.rgb.b
-        )?;
-        Ok(())
+fn print_version(verbose: bool) {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    if !verbose {
+        return;
     }
+    let enabled = OPTIONAL_FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+    let features = if enabled.is_empty() {
+        "none".to_string()
+    } else {
+        enabled.join(", ")
+    };
+    println!("features: {}", features);
+    println!(
+        "target: {}-{} ({})",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        }
+    );
 }
 
 /// Command line that sends pixels to a pixelflut server
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, disable_version_flag = true)]
 struct Config {
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+    /// Together with --version, also report the build configuration
+    #[arg(long, requires = "version")]
+    verbose: bool,
     #[arg(short, long, default_value = "pixelflut:1234")]
     url: String,
     #[arg(default_value = "0")]
     x: u16,
     #[arg(default_value = "0")]
     y: u16,
+    /// Reduce the sprite to N bits per channel (e.g. 332, 565 or 4)
+    #[arg(long)]
+    color_depth: Option<ColorDepth>,
+    /// Act as a pixelflut relay on this port, drawing Pac-Man over the relayed pixels
+    #[arg(long)]
+    relay_port: Option<u16>,
+    /// Write a PNG snapshot of the rendered scene to this path
+    #[arg(long)]
+    snapshot: Option<String>,
+    /// Seconds between periodic snapshots
+    #[arg(long, default_value = "5")]
+    snapshot_interval: u64,
+    /// Write a single snapshot after the first frame and exit
+    #[arg(long, requires = "snapshot")]
+    snapshot_and_exit: bool,
+    /// Pad the proportionally scaled sprite with transparency to a square of --size
+    #[arg(long)]
+    respect_aspect: bool,
+    /// Squash the sprite into a square of --size instead of keeping its aspect ratio
+    #[arg(long, conflicts_with = "respect_aspect")]
+    stretch: bool,
+    /// Filter used to scale the sprite to --size, smoother ones suit photographic sprites
+    #[arg(long, value_enum, default_value = "nearest")]
+    filter: ResizeFilter,
+    /// Sprite size in pixels, 0 keeps the GIF's native frame size
+    #[arg(long, default_value = "60")]
+    size: u32,
+    /// Show every animation frame this long instead of following the delays stored in the GIF,
+    /// can be changed later through /config
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=10_000))]
+    frame_duration_ms: Option<u64>,
+    /// Pixels moved per loop iteration, can be changed later through /config
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..=1_000))]
+    movement_speed: u16,
+    /// Move one step per beat at this tempo instead of continuously
+    #[arg(long)]
+    bpm: Option<u32>,
+    /// Replace an exact sprite color, e.g. FFFF00=FF00FF (repeatable)
+    #[arg(long)]
+    remap: Vec<ColorRemap>,
+    /// Play the sprite animation backwards
+    #[arg(long)]
+    reverse_animation: bool,
+    /// Send the sprite in grayscale for monochrome walls
+    #[arg(long)]
+    no_color: bool,
+    /// Brighten darker sprite pixels to at least this luminance (0-255), to stand out on bright art
+    #[arg(long)]
+    min_brightness: Option<u8>,
+    /// Draw Pac-Man procedurally instead of using the embedded GIF
+    #[arg(long)]
+    procedural: bool,
+    /// Radius of the procedural Pac-Man in pixels
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u32).range(1..))]
+    radius: u32,
+    /// Color of the procedural Pac-Man
+    #[arg(long, default_value = "FFFF00")]
+    color: Color,
+    /// Degrees the procedural mouth opens or closes per animation frame
+    #[arg(long, default_value = "15")]
+    chomp_speed: f32,
+    /// Flush the connection every N sprite rows so the sprite paints progressively
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    flush_rows: Option<u32>,
+    /// Maximum pixels per second, used when the server doesn't advertise a limit itself
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_pps: Option<u32>,
+    /// Maximum bytes per second sent to each server, shared by its --connections, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    max_bps: u64,
+    /// Resend bright sprite pixels more often than dark ones
+    #[arg(long)]
+    brightness_priority: bool,
+    /// Leave a fading trail colored by the direction of travel
+    #[arg(long)]
+    direction_trail: bool,
+    /// Paint black over pixels Pac-Man no longer covers instead of leaving his old frames behind
+    #[arg(long, conflicts_with = "direction_trail")]
+    erase_trail: bool,
+    /// Paint the sprite's box in this color under it, so it stands out on a busy canvas
+    #[arg(long, value_name = "RRGGBB")]
+    bg: Option<Color>,
+    /// Number of movement steps until the trail has faded out
+    #[arg(long, default_value = "8", value_parser = clap::value_parser!(u32).range(1..))]
+    trail_length: u32,
+    /// Trail colors for moving right, left, up and down
+    #[arg(long, default_value = "FF0000,00FF00,0000FF,FF00FF", value_parser = parse_trail_colors)]
+    trail_colors: [Color; 4],
+    /// Only send every Nth sprite pixel per pass, covering the rest over the next passes
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    every_nth_pixel: u64,
+    /// Measure the connection at startup and pick the decimation level automatically
+    #[arg(long, conflicts_with = "every_nth_pixel")]
+    auto_optimize: bool,
+    /// Built-in sprite to draw (pac, ghost or ball)
+    #[arg(long, default_value = "pac", value_parser = parse_theme)]
+    theme: &'static Theme,
+    /// Stop the chomping animation while Pac-Man is moving or while it stands still
+    #[arg(long)]
+    freeze_animation: Option<FreezeAnimation>,
+    /// Read back a stationary sprite and only redraw pixels that were painted over. Reads only
+    /// go to the main server, so this can't be combined with --wall
+    #[arg(long, conflicts_with = "wall")]
+    verify_resend: bool,
+    /// Maximum number of pixels read back per verification or --canvas-delta frame
+    #[arg(long, default_value = "256", value_parser = clap::value_parser!(u32).range(1..))]
+    readback_limit: u32,
+    /// Download the sprite GIF or PNG from an http(s) URL instead of using a built-in theme
+    #[arg(long, value_parser = parse_sprite_url)]
+    sprite: Option<String>,
+    /// Load the sprite GIF from this file instead of using a built-in theme
+    #[arg(long, conflicts_with_all = ["sprite", "procedural"])]
+    gif: Option<std::path::PathBuf>,
+    /// Derive the turned frames on every draw once the sprite needs more than this many frames
+    /// for all four directions, trading CPU time for memory with large sprites
+    #[arg(long)]
+    max_cached_frames: Option<usize>,
+    /// Outline the sprite's bounding box for debugging
+    #[arg(long)]
+    debug_bbox: bool,
+    /// Color of the debug bounding box
+    #[arg(long, default_value = "FF00FF")]
+    debug_bbox_color: Color,
+    /// Run unattended, rotating through the demo features
+    #[arg(long)]
+    demo: bool,
+    /// Seconds each demo feature stays active
+    #[arg(long, default_value = "15", value_parser = clap::value_parser!(u64).range(1..))]
+    demo_interval: u64,
+    /// Demo features to rotate through
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "wander,themes,trail,reverse"
+    )]
+    demo_features: Vec<DemoFeature>,
+    /// Send all pixels of one color before moving on to the next color
+    #[arg(long)]
+    group_by_color: bool,
+    /// Send a frame's pixels along a Hilbert curve, so partially drawn frames look evenly filled
+    #[arg(long, conflicts_with = "group_by_color")]
+    hilbert_order: bool,
+    /// Play the death animation and respawn when told to die ('x' or POST /x)
+    #[arg(long)]
+    death_animation: bool,
+    /// After spawning, ignore being told to die for this long while Pac-Man blinks
+    #[arg(long, default_value_t = 0, requires = "death_animation")]
+    spawn_protect_ms: u64,
+    /// Directions Pac-Man may move in, control commands for all others are ignored
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 1..,
+        default_value = "right,left,up,down,up-left,up-right,down-left,down-right"
+    )]
+    allowed_directions: Vec<Direction>,
+    /// What Pac-Man, the swarm and the players' sprites do at the edge of the canvas
+    #[arg(long, value_enum, default_value = "wrap")]
+    boundary: Boundary,
+    /// Also draw to a local Linux framebuffer like /dev/fb0, its size is used as the canvas size
+    #[arg(long)]
+    framebuffer: Option<std::path::PathBuf>,
+    /// Create a FIFO at this path and read control commands (w, a, s, d, x) from it line by line
+    #[cfg(unix)]
+    #[arg(long)]
+    control_fifo: Option<std::path::PathBuf>,
+    /// Number of additional Pac-Men that wander the canvas on their own
+    #[arg(long, default_value_t = 0)]
+    swarm: u32,
+    /// Give each socket connection its own Pac-Man, for up to this many players at a time
+    #[arg(long, default_value_t = 0)]
+    max_players: usize,
+    /// Tint every swarm member with its own random color
+    #[arg(long, requires = "swarm")]
+    swarm_color_random: bool,
+    /// Steer every swarm member with the controls too, so they all move in unison
+    #[arg(long, requires = "swarm")]
+    mirror_control: bool,
+    /// Serve the control page from index.html in this directory instead of the built-in one
+    #[arg(long)]
+    web_root: Option<std::path::PathBuf>,
+    /// Reread the control page from --web-root on every request instead of once at startup
+    #[arg(long, requires = "web_root")]
+    web_watch: bool,
+    /// Keep retrying to connect for this many seconds while the server is unreachable
+    #[arg(long, default_value_t = 0)]
+    connect_retry_secs: u64,
+    /// Print the traffic the configuration would generate and exit without connecting
+    #[arg(long)]
+    dry_run_stats: bool,
+    /// Write the pixel commands to FILE, or to stdout without one, instead of connecting to a
+    /// server. The canvas is --canvas-size, 1920x1080 by default
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        conflicts_with_all = ["probe", "auto_optimize", "verify_resend", "canvas_delta", "wall", "connections"]
+    )]
+    dry_run: Option<std::path::PathBuf>,
+    /// Stop once this many pixels were sent in total, commands past it are never sent
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_total_pixels: Option<u64>,
+    /// Erase Pac-Man when stopping at --max-total-pixels, these pixels are sent beyond the budget
+    #[arg(long, requires = "max_total_pixels")]
+    clear_at_limit: bool,
+    /// Retry a pixel a few times when writing it fails with a transient error
+    #[arg(long)]
+    pixel_retry: bool,
+    /// Extend the canvas with another server whose canvas starts at X,Y, as HOST:PORT@X,Y
+    #[arg(long)]
+    wall: Vec<WallSpec>,
+    /// Send pixels to the main server in the binary `PB` encoding instead of `PX` text commands
+    #[arg(long)]
+    binary: bool,
+    /// Draw over this many parallel connections to the server for more throughput
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..=64))]
+    connections: u32,
+    /// Number of control commands that can wait for the main loop
+    #[arg(
+        long,
+        default_value = "16",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    control_queue_depth: usize,
+    /// Which command to discard when the control queue is full
+    #[arg(long, value_enum, default_value = "drop-oldest")]
+    control_drop_policy: DropPolicy,
+    /// Address the control socket listens on, empty to disable it
+    #[arg(long, default_value = "0.0.0.0:1234")]
+    control_addr: String,
+    /// Address the web control listens on, empty to disable it
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    web_addr: String,
+    /// Periodically print how fast Pac-Man actually moves
+    #[arg(long)]
+    movement_stats: bool,
+    /// Append a CSV row per drawn frame with its position, direction and pixel count to this file
+    #[arg(long)]
+    telemetry: Option<std::path::PathBuf>,
+    /// Print a short status line every N seconds to show the client is alive, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    heartbeat_secs: u64,
+    /// Walk back and forth between two points without any control input
+    #[arg(long, num_args = 2, value_names = ["X1,Y1", "X2,Y2"], value_parser = parse_point)]
+    patrol: Vec<(u16, u16)>,
+    /// Milliseconds to dwell at each end of the patrol
+    #[arg(long, default_value_t = 0, requires = "patrol")]
+    patrol_pause_ms: u64,
+    /// Stop with an error when a pixel would land off the canvas instead of skipping it
+    #[arg(long)]
+    strict_bounds: bool,
+    /// Let the sprite grow and shrink over time
+    #[arg(long)]
+    pulse: bool,
+    /// Milliseconds for one grow and shrink cycle of --pulse
+    #[arg(long, default_value_t = 2000)]
+    pulse_period_ms: u64,
+    /// How far --pulse scales the sprite up and down, as a fraction of its size
+    #[arg(long, default_value_t = 0.25, value_parser = parse_pulse_amplitude)]
+    pulse_amplitude: f32,
+    /// Write one pixel at the start position, read it back to check the connection and exit
+    #[arg(long)]
+    probe: bool,
+    /// Draw COUNT (up to 256) additional Pac-Men circling X,Y at RADIUS, SPEED degrees per second
+    #[arg(long, value_name = "X,Y,RADIUS,SPEED,COUNT")]
+    orbit: Option<Orbit>,
+    /// Canvas size as WIDTHxHEIGHT instead of asking the server with SIZE
+    #[arg(long, env = "PIXELFLUT_CANVAS_SIZE", value_parser = parse_canvas_size)]
+    canvas_size: Option<(u16, u16)>,
+    /// Skip sprite pixels whose alpha is at or below this, by default only fully transparent ones
+    #[arg(long, default_value_t = 0)]
+    alpha_threshold: u8,
+    /// Send translucent pixels as PX x y rrggbbaa so servers that support it blend them
+    #[arg(long)]
+    alpha: bool,
+    /// Briefly brighten Pac-Man whenever he changes direction
+    #[arg(long)]
+    turn_flash: bool,
+    /// Number of animation frames the --turn-flash takes to fade out
+    #[arg(long, default_value_t = 3)]
+    turn_flash_frames: u32,
+    /// Only send pixels whose color differs from what was last sent to the same spot
+    #[arg(long, conflicts_with = "verify_resend")]
+    delta: bool,
+    /// Like --delta, but also read back up to --readback-limit sprite pixels per frame from the
+    /// canvas, so pixels painted over by others are redrawn and intact ones are not resent.
+    /// Can't be combined with --wall, reads only go to the main server
+    #[arg(long, conflicts_with_all = ["verify_resend", "wall"])]
+    canvas_delta: bool,
+    /// Send one OFFSET per frame and sprite-local pixel coordinates, if the server's HELP lists
+    /// OFFSET. Sprites hanging over the canvas edge are clipped instead of wrapped
+    #[arg(
+        long,
+        conflicts_with_all = ["delta", "canvas_delta", "wall", "binary", "connections", "framebuffer"]
+    )]
+    use_offset: bool,
+    /// Also write every command sent to the server to PATH, with timing marks for --replay.
+    /// Gzip compressed when PATH ends in .gz
+    #[arg(long, value_name = "PATH")]
+    record: Option<std::path::PathBuf>,
+    /// Send the commands recorded with --record at their recorded pace and exit
+    #[arg(long, value_name = "PATH")]
+    replay: Option<std::path::PathBuf>,
 }
 
-enum Direction {
-    Right,
-    Left,
-    Up,
-    Down,
+fn parse_sprite_url(s: &str) -> Result<String, String> {
+    if s.starts_with("http://") || s.starts_with("https://") {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "Invalid sprite URL '{}': expected http:// or https://",
+            s
+        ))
+    }
 }
 
-fn write_frame_to_stream<T: Write>(
-    frame: &image::RgbaImage,
-    position: Coordinates,
-    buffer: &mut T,
-    canvas_size: (u16, u16),
-) -> io::Result<()> {
-    for (x, y, &color) in frame.enumerate_pixels() {
-        Pixel {
-            point: Coordinates {
-                x: x as u16,
-                y: y as u16,
-                bounds: canvas_size,
-            } + position,
-            rgb: color.into(),
-        }
-        .write(buffer)?;
+fn parse_trail_colors(s: &str) -> Result<[Color; 4], String> {
+    s.split(',')
+        .map(Color::from_str)
+        .collect::<Result<Vec<_>, _>>()?
+        .try_into()
+        .map_err(|_| format!("Invalid trail colors '{}': expected four colors", s))
+}
+
+fn parse_pulse_amplitude(s: &str) -> Result<f32, String> {
+    let amplitude: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..1.0).contains(&amplitude) {
+        return Err("the amplitude must be at least 0 and less than 1".to_string());
     }
-    Ok(())
+    Ok(amplitude)
 }
 
-fn get_canvas_size(mut stream: &TcpStream) -> (u16, u16) {
-    let mut reader = io::BufReader::new(stream.try_clone().expect("Failed to clone stream"));
+fn parse_canvas_size(s: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid canvas size '{}': expected WIDTHxHEIGHT", s);
+    let (width, height) = s.split_once(['x', 'X']).ok_or_else(invalid)?;
+    let size: (u16, u16) = (
+        width.trim().parse().map_err(|_| invalid())?,
+        height.trim().parse().map_err(|_| invalid())?,
+    );
+    if size.0 == 0 || size.1 == 0 {
+        return Err(invalid());
+    }
+    Ok(size)
+}
 
-    stream
-        .write_all(b"SIZE\n")
-        .expect("Failed to send size request to the server");
+fn parse_point(s: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid point '{}': expected X,Y", s);
+    let (x, y) = s.split_once(',').ok_or_else(invalid)?;
+    Ok((
+        x.trim().parse().map_err(|_| invalid())?,
+        y.trim().parse().map_err(|_| invalid())?,
+    ))
+}
 
-    let mut buffer = String::new();
-    reader
-        .read_line(&mut buffer)
-        .expect("Failed to read the server response from the stream");
+// The sprite color changes the arguments ask for
+fn color_options(args: &Config) -> ColorOptions {
+    ColorOptions {
+        remap: args
+            .remap
+            .iter()
+            .map(|remap| (remap.from, remap.to))
+            .collect(),
+        grayscale: args.no_color,
+        min_brightness: args.min_brightness,
+        depth: args.color_depth,
+        background: args.bg,
+    }
+}
 
-    let mut parts = buffer.split_whitespace();
+// Write a pixel and read it back to check that drawing works end to end, then restore it
+fn probe(stream: &TcpStream, x: u16, y: u16) -> Result<(), Box<dyn Error>> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
 
-    parts
-        .next()
-        .expect("Failed parsing of size response: Response is empty");
+    let original = read_pixel(stream, &mut reader, x, y)?;
+    // Pick a color that differs from what is there, so the write is visible in the readback
+    let probe_color = match original {
+        Some(color) => Color {
+            r: !color.r,
+            g: !color.g,
+            b: !color.b,
+        },
+        None => Color {
+            r: 255,
+            g: 0,
+            b: 255,
+        },
+    };
 
-    let width = parts
-        .next()
-        .and_then(|f| f.parse::<u16>().ok())
-        .expect("Failed parsing of size response");
+    let start = std::time::Instant::now();
+    let mut writer = stream;
+    Pixel {
+        point: Coordinates {
+            x,
+            y,
+            bounds: (u16::MAX, u16::MAX),
+        },
+        rgb: probe_color,
+        alpha: None,
+    }
+    .write(&mut writer)?;
+    let readback = read_pixel(stream, &mut reader, x, y)?;
+    let round_trip = start.elapsed();
 
-    let height = parts
-        .next()
-        .and_then(|f| f.parse::<u16>().ok())
-        .expect("Failed parsing of size response");
+    if let Some(color) = original {
+        Pixel {
+            point: Coordinates {
+                x,
+                y,
+                bounds: (u16::MAX, u16::MAX),
+            },
+            rgb: color,
+            alpha: None,
+        }
+        .write(&mut writer)?;
+    }
+    stream.set_read_timeout(None)?;
+
+    match readback {
+        Some(color) if color == probe_color => {
+            println!(
+                "Probe at {},{} succeeded: wrote and read back {} in {:.1} ms",
+                x,
+                y,
+                probe_color,
+                round_trip.as_secs_f64() * 1000.0
+            );
+            Ok(())
+        }
+        Some(color) => Err(format!(
+            "Probe at {},{} failed: wrote {} but read back {}",
+            x, y, probe_color, color
+        )
+        .into()),
+        None => {
+            println!(
+                "Probe at {},{}: wrote {}, but the server does not answer reads so it could not be verified",
+                x, y, probe_color
+            );
+            Ok(())
+        }
+    }
+}
+
+// How the configured sprite is scaled to --size
+fn frame_fit(args: &Config) -> FrameFit {
+    if args.stretch {
+        FrameFit::Stretch
+    } else if args.respect_aspect {
+        FrameFit::Letterbox
+    } else {
+        FrameFit::Proportional
+    }
+}
+
+// The right facing frames of the configured sprite and their timing
+fn load_sprite_frames(
+    args: &Config,
+) -> Result<(Vec<image::RgbaImage>, FrameTimeline), Box<dyn Error>> {
+    if let Some(path) = &args.gif {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read sprite {}: {}", path.display(), e))?;
+        return Ok(
+            decode_sprite(&bytes, args.size, frame_fit(args), args.filter)
+                .map_err(|e| format!("Failed to decode sprite {}: {}", path.display(), e))?,
+        );
+    }
+    // Prefer a downloaded sprite, but keep running with the built-in theme if that fails
+    let downloaded_frames = args.sprite.as_deref().and_then(|url| {
+        match fetch_sprite(url).and_then(|bytes| {
+            Ok(decode_sprite(
+                &bytes,
+                args.size,
+                frame_fit(args),
+                args.filter,
+            )?)
+        }) {
+            Ok(frames) => Some(frames),
+            Err(e) => {
+                eprintln!(
+                    "Failed to load sprite from {}, using the {} theme instead: {}",
+                    url, args.theme.name, e
+                );
+                None
+            }
+        }
+    });
+    Ok(if args.procedural {
+        (
+            procedural_frames(args.radius, args.color, args.chomp_speed),
+            FrameTimeline::default(),
+        )
+    } else if let Some(frames) = downloaded_frames {
+        frames
+    } else {
+        load_theme(args.theme, args.size, frame_fit(args), args.filter)
+            .expect("Failed to decode gif file")
+    })
+}
 
-    (width, height)
+// Connect to every --wall and ask for its size
+fn connect_walls(args: &Config) -> io::Result<Vec<Wall>> {
+    let mut walls = Vec::with_capacity(args.wall.len());
+    for spec in &args.wall {
+        let wall_connection = connect_with_retry(
+            &spec.address,
+            std::time::Duration::from_secs(args.connect_retry_secs),
+        )?;
+        let size = get_canvas_size(&wall_connection)?;
+        println!(
+            "Wall {} covers {}x{} pixels at {},{}",
+            spec.address, size.0, size.1, spec.x, spec.y
+        );
+        walls.push(Wall {
+            offset: (spec.x, spec.y),
+            size,
+            writer: io::BufWriter::new(ByteRateLimiter::new(
+                RetryOnWouldBlock::new(wall_connection),
+                args.max_bps,
+            )),
+        });
+    }
+    Ok(walls)
 }
 
+// Where --dry-run writes the commands, stdout without a file
+fn dry_run_output(args: &Config) -> io::Result<Box<dyn Write>> {
+    Ok(match args.dry_run.as_deref() {
+        Some(path) if path != std::path::Path::new("-") => Box::new(std::fs::File::create(path)?),
+        _ => Box::new(io::stdout()),
+    })
+}
+
+// Send a --record recording at its recorded pace and nothing else. No sprite is loaded and no
+// input is read, the commands only pass the rate limits, the binary encoding and the walls
+fn replay(args: &Config, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+    let recording = open_recording(path)?;
+    let (output, main_size): (Box<dyn Write>, _) = match args.dry_run {
+        Some(_) => (
+            dry_run_output(args)?,
+            args.canvas_size.unwrap_or(DRY_RUN_CANVAS_SIZE),
+        ),
+        None => {
+            let connection = connect_with_retry(
+                &args.url,
+                std::time::Duration::from_secs(args.connect_retry_secs),
+            )?;
+            let size = match args.canvas_size {
+                Some(size) => size,
+                None => get_canvas_size(&connection)?,
+            };
+            let connection = Reconnecting::new(connection, &args.url, size);
+            (Box::new(RetryOnWouldBlock::new(connection)), size)
+        }
+    };
+    let mut writer = io::BufWriter::new(WallRouter {
+        inner: PixelRateLimiter::new(
+            BinaryPixels::new(ByteRateLimiter::new(output, args.max_bps), args.binary),
+            args.max_pps,
+        ),
+        inner_size: main_size,
+        walls: connect_walls(args)?,
+        pending: Vec::new(),
+    });
+    let commands = replay_recording(recording, &mut writer)?;
+    writer.flush()?;
+    println!("Replayed {} commands from {}", commands, path.display());
+    Ok(())
+}
+
+// Canvas assumed by --dry-run-stats without --canvas-size, it never asks a server for its size
+const DRY_RUN_CANVAS_SIZE: (u16, u16) = (1920, 1080);
+
 fn main() -> Result<(), Box<dyn Error>> {
     println!("Start pixel client");
 
     // Parse the command line arguments
     let args = Config::parse();
 
-    let pacman_size: u32 = 60;
+    if args.version {
+        print_version(args.verbose);
+        return Ok(());
+    }
+
+    // A replay only sends the recorded commands, nothing is animated or steered
+    if let Some(path) = &args.replay {
+        return replay(&args, path);
+    }
 
-    let (direction_tx, direction_rx) = channel();
+    let tunables = Arc::new(Mutex::new(Tunables {
+        frame_duration_ms: args.frame_duration_ms,
+        speed: args.movement_speed,
+        repeat: 10,
+        tint: None,
+    }));
 
-    let direction_tx_console = direction_tx.clone();
+    if args.dry_run_stats {
+        let canvas_size = args.canvas_size.unwrap_or(DRY_RUN_CANVAS_SIZE);
+        let sprite = SpriteFrames::new(
+            apply_color_options(
+                fit_to_canvas(load_sprite_frames(&args)?.0, canvas_size, args.filter),
+                &color_options(&args),
+            ),
+            None,
+        );
+        let options = RenderOptions {
+            flush_rows: args.flush_rows,
+            brightness_priority: args.brightness_priority,
+            every_nth_pixel: args.every_nth_pixel,
+            debug_bbox: args.debug_bbox.then_some(args.debug_bbox_color),
+            tint: None,
+            retry_pixels: args.pixel_retry,
+            strict_bounds: args.strict_bounds,
+            alpha_threshold: args.alpha_threshold,
+            blend_alpha: args.alpha,
+            flash: 0.0,
+            use_offset: args.use_offset,
+        };
+        let position = Coordinates {
+            x: args.x,
+            y: args.y,
+            bounds: canvas_size,
+        };
+        let repeat = tunables.lock().unwrap().repeat;
+        print_dry_run_stats(
+            &sprite.right,
+            position,
+            &options,
+            repeat,
+            args.max_pps,
+            args.binary,
+        )?;
+        return Ok(());
+    }
+
+    // Every frame drawn in the last pass and where, for the web preview and erasing on exit
+    let preview: Arc<Mutex<Vec<DrawnFrame>>> = Arc::new(Mutex::new(Vec::new()));
+    let metrics = Arc::new(Mutex::new(Metrics::new((args.x, args.y), Direction::Right)));
+
+    let command_queue = CommandQueue::new(args.control_queue_depth, args.control_drop_policy);
+    // Every control server reports whether it could start, for the summary below
+    let (bind_tx, bind_rx) = channel::<BindOutcome>();
+    let mut control_servers = 0;
+
+    let command_tx_console = command_queue.clone();
     let _input_thread = std::thread::spawn(move || {
         let term = Term::stdout();
+        let mut backoff = ErrorBackoff::new();
+        // A second direction key pressed right after a perpendicular one turns diagonally
+        let mut diagonal_keys = DiagonalKeys::default();
         loop {
-            // Read character
-            let c = term.read_char().expect("Failed to read input");
-            let direction = match c {
-                'w' => Direction::Up,
-                'a' => Direction::Left,
-                's' => Direction::Down,
-                'd' => Direction::Right,
+            // Read a key, the terminal is only put into raw mode for the duration of the read
+            let key = match term.read_key() {
+                Ok(key) => {
+                    backoff.succeeded();
+                    key
+                }
+                Err(e) if backoff.failed("Reading console input", &e) => continue,
+                Err(_) => return,
+            };
+            let command = match key {
+                Key::Char('w') | Key::ArrowUp => Command::Move(Direction::Up),
+                Key::Char('a') | Key::ArrowLeft => Command::Move(Direction::Left),
+                Key::Char('s') | Key::ArrowDown => Command::Move(Direction::Down),
+                Key::Char('d') | Key::ArrowRight => Command::Move(Direction::Right),
+                // Shifted keys only turn Pac-Man
+                Key::Char('W') => Command::Face(Direction::Up),
+                Key::Char('A') => Command::Face(Direction::Left),
+                Key::Char('S') => Command::Face(Direction::Down),
+                Key::Char('D') => Command::Face(Direction::Right),
+                Key::Char('x') => Command::Die,
+                Key::Char('q') => Command::Quit,
+                Key::Char('p') => Command::Pause,
                 _ => continue,
             };
-            direction_tx_console
-                .send(direction)
-                .expect("Failed to move keypress to main thread");
+            let command = match command {
+                Command::Move(direction) => {
+                    Command::Move(diagonal_keys.press(direction, std::time::Instant::now()))
+                }
+                command => command,
+            };
+            command_tx_console.send(command);
         }
     });
 
-    let direction_tx_socket = direction_tx.clone();
-    let _input_socket_thread = std::thread::spawn(move || {
-        let listener = match std::net::TcpListener::bind("0.0.0.0:1234") {
-            Ok(listener) => listener,
-            Err(e) => {
-                eprintln!("Socket based control is unavailable: {}", e);
+    let (player_tx, player_rx) = channel::<PlayerEvent>();
+    if !args.control_addr.is_empty() {
+        let command_tx_socket = command_queue.clone();
+        let max_players = args.max_players;
+        let seats = PlayerSeats::new(args.max_players);
+        let bind_tx_socket = bind_tx.clone();
+        control_servers += 1;
+        let control_addr = args.control_addr.clone();
+        let _input_socket_thread = std::thread::spawn(move || {
+            let name = format!("socket on {}", control_addr);
+            let bind = || std::net::TcpListener::bind(&control_addr);
+            let Some(listener) = bind_control(&name, &bind_tx_socket, bind) else {
                 return;
-            }
-        };
-        let mut connection_pool = Vec::new();
-        for stream in listener.incoming() {
-            let stream = stream.expect("Failed to get stream");
-            let peer = stream.peer_addr().expect("Failed to get peer address");
-            println!(
-                "Remote control connected. (IP: {} | Connection: {})",
-                peer,
-                connection_pool.len()
-            );
-            let tx_handle = direction_tx_socket.clone();
-            connection_pool.push(std::thread::spawn(move || {
-                let mut reader =
-                    io::BufReader::new(stream.try_clone().expect("Failed to clone stream"));
-                loop {
-                    let mut buffer = String::new();
-                    reader
-                        .read_line(&mut buffer)
-                        .expect("Failed to read the server response from the stream");
-
-                    // Break if the connection is closed
-                    if buffer.is_empty() {
-                        println!("Remote control disconnected! (IP: {})", peer);
-                        break;
+            };
+            let mut connection_pool = Vec::new();
+            let mut backoff = ErrorBackoff::new();
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => {
+                        backoff.succeeded();
+                        stream
                     }
-
-                    let direction = match buffer.trim() {
-                        "w" => Direction::Up,
-                        "a" => Direction::Left,
-                        "s" => Direction::Down,
-                        "d" => Direction::Right,
-                        _ => continue,
-                    };
-                    tx_handle
-                        .send(direction)
-                        .expect("Failed to move socket input to main thread");
+                    Err(e) if backoff.failed("Accepting a control connection", &e) => continue,
+                    Err(_) => return,
+                };
+                let peer = stream.peer_addr().expect("Failed to get peer address");
+                // With players, each connection steers a Pac-Man of its own
+                let player = (max_players > 0).then_some(connection_pool.len());
+                if player.is_some() && !seats.take() {
+                    println!(
+                        "Remote control rejected, all players are taken. (IP: {})",
+                        peer
+                    );
+                    writeln!(&stream, "All {} players are taken", max_players).ok();
+                    continue;
                 }
-            }));
-        }
-    });
+                println!(
+                    "Remote control connected. (IP: {} | Connection: {})",
+                    peer,
+                    connection_pool.len()
+                );
+                let tx_handle = command_tx_socket.clone();
+                let player_tx = player_tx.clone();
+                let seats = seats.clone();
+                if let Some(player) = player {
+                    player_tx.send(PlayerEvent::Joined(player)).ok();
+                }
+                connection_pool.push(std::thread::spawn(move || {
+                    // A failed read ends the connection just like the client closing it
+                    let reader = stream.try_clone().map(io::BufReader::new);
+                    if let Err(e) = &reader {
+                        eprintln!("Failed to read remote control {}: {}", peer, e);
+                    }
+                    for line in reader.into_iter().flat_map(BufRead::lines) {
+                        let line = match line {
+                            Ok(line) => line,
+                            Err(e) => {
+                                eprintln!("Failed to read remote control {}: {}", peer, e);
+                                break;
+                            }
+                        };
+                        let Some(command) = parse_command(&line) else {
+                            continue;
+                        };
+                        match player {
+                            Some(player) => {
+                                player_tx.send(PlayerEvent::Command(player, command)).ok();
+                            }
+                            None => tx_handle.send(command),
+                        }
+                    }
+                    println!("Remote control disconnected! (IP: {})", peer);
+                    if let Some(player) = player {
+                        seats.release();
+                        player_tx.send(PlayerEvent::Left(player)).ok();
+                    }
+                }));
+            }
+        });
+    }
 
-    let direction_tx_web = direction_tx.clone();
-    let _input_web_thread = std::thread::spawn(move || {
-        let server = match Server::http("0.0.0.0:8080") {
-            Ok(server) => server,
-            Err(e) => {
-                eprintln!("Web based control is unavailable: {}", e);
+    #[cfg(unix)]
+    if let Some(path) = args.control_fifo.clone() {
+        let command_tx_fifo = command_queue.clone();
+        let bind_tx_fifo = bind_tx.clone();
+        control_servers += 1;
+        let _input_fifo_thread = std::thread::spawn(move || {
+            let name = format!("FIFO at {}", path.display());
+            if bind_control(&name, &bind_tx_fifo, || create_fifo(&path)).is_none() {
                 return;
             }
-        };
-        for request in server.incoming_requests() {
-            let url = request.url();
-            let method = request.method();
-            match (method.as_str(), url) {
-                ("GET", "/") => {
-                    let response = Response::from_string(include_str!("index.html"))
-                        .with_status_code(200)
-                        .with_header(Header::from_str("Content-Type: text/html").unwrap());
-                    request.respond(response).unwrap();
-                }
-                // Match the URL substring and method
-                ("POST", cmd) => {
-                    let direction = match cmd {
-                        "/w" => Some(Direction::Up),
-                        "/a" => Some(Direction::Left),
-                        "/s" => Some(Direction::Down),
-                        "/d" => Some(Direction::Right),
-                        _ => None,
-                    };
-                    if let Some(direction) = direction {
-                        direction_tx_web
-                            .send(direction)
-                            .expect("Failed to move web input to main thread");
-                        request
-                            .respond(Response::empty(StatusCode::from(200)))
-                            .unwrap();
-                    } else {
+            read_control_fifo(&path, &command_tx_fifo);
+        });
+    }
+
+    if !args.web_addr.is_empty() {
+        let command_tx_web = command_queue.clone();
+        let tunables_web = tunables.clone();
+        let preview_web = preview.clone();
+        let (alpha_threshold, blend_alpha) = (args.alpha_threshold, args.alpha);
+        let metrics_web = metrics.clone();
+        let bind_tx_web = bind_tx.clone();
+        control_servers += 1;
+        let web_addr = args.web_addr.clone();
+        let web_root = args.web_root.clone();
+        let web_watch = args.web_watch;
+        let _input_web_thread = std::thread::spawn(move || {
+            let name = format!("web on {}", web_addr);
+            let Some(server) = bind_control(&name, &bind_tx_web, || Server::http(&web_addr)) else {
+                return;
+            };
+            // Without watching the page is read once, so later edits need a restart
+            let control_page = load_control_page(web_root.as_deref());
+            let mut preview_cache: Option<(std::time::Instant, Vec<u8>)> = None;
+            for mut request in server.incoming_requests() {
+                // Ignore query strings, they are only used to defeat browser caches
+                let url = request
+                    .url()
+                    .split('?')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let method = request.method().clone();
+                match (method.as_str(), url.as_str()) {
+                    ("GET", "/") => {
+                        let page = if web_watch {
+                            load_control_page(web_root.as_deref())
+                        } else {
+                            control_page.clone()
+                        };
+                        let response = Response::from_string(page)
+                            .with_status_code(200)
+                            .with_header(Header::from_str("Content-Type: text/html").unwrap());
+                        request.respond(response).unwrap();
+                    }
+                    ("GET", "/preview.png") => {
+                        let cache_fresh = preview_cache.as_ref().is_some_and(|(rendered, _)| {
+                            rendered.elapsed() < PREVIEW_CACHE_DURATION
+                        });
+                        if !cache_fresh {
+                            let drawn = preview_web.lock().unwrap();
+                            let scene = drawn.first().map(|first| {
+                                render_scene(
+                                    &drawn,
+                                    first.position.bounds,
+                                    alpha_threshold,
+                                    blend_alpha,
+                                )
+                            });
+                            drop(drawn);
+                            preview_cache = scene.and_then(|scene| {
+                                let mut png = Vec::new();
+                                match scene
+                                    .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+                                {
+                                    Ok(()) => Some((std::time::Instant::now(), png)),
+                                    Err(e) => {
+                                        eprintln!("Failed to encode the preview: {}", e);
+                                        None
+                                    }
+                                }
+                            });
+                        }
+                        let response = match &preview_cache {
+                            Some((_, png)) => Response::from_data(png.clone())
+                                .with_header(Header::from_str("Content-Type: image/png").unwrap()),
+                            None => Response::from_string("Nothing has been drawn yet")
+                                .with_status_code(503),
+                        };
+                        request.respond(response).unwrap();
+                    }
+                    ("GET", "/metrics") => {
+                        let metrics = metrics_web.lock().unwrap().to_json();
+                        let response = Response::from_string(metrics.to_string()).with_header(
+                            Header::from_str("Content-Type: application/json").unwrap(),
+                        );
+                        request.respond(response).unwrap();
+                    }
+                    ("GET", "/config") => {
+                        let config = tunables_web.lock().unwrap().to_json();
+                        let response = Response::from_string(config.to_string()).with_header(
+                            Header::from_str("Content-Type: application/json").unwrap(),
+                        );
+                        request.respond(response).unwrap();
+                    }
+                    ("POST", "/config") => {
+                        let mut body = String::new();
+                        let patch = request
+                            .as_reader()
+                            .take(MAX_CONFIG_BODY_BYTES)
+                            .read_to_string(&mut body)
+                            .map_err(|e| e.to_string())
+                            .and_then(|_| serde_json::from_str(&body).map_err(|e| e.to_string()));
+                        let mut tunables = tunables_web.lock().unwrap();
+                        let response = match patch.and_then(|patch| tunables.patched(&patch)) {
+                            Ok(patched) => {
+                                *tunables = patched;
+                                Response::from_string(tunables.to_json().to_string()).with_header(
+                                    Header::from_str("Content-Type: application/json").unwrap(),
+                                )
+                            }
+                            Err(e) => Response::from_string(e).with_status_code(400),
+                        };
+                        request.respond(response).unwrap();
+                    }
+                    // Match the URL substring and method
+                    ("POST", cmd) => {
+                        let command = if let Some(name) = cmd.strip_prefix("/theme/") {
+                            find_theme(name).map(Command::Theme)
+                        } else if let Some(target) = cmd.strip_prefix("/pointer/") {
+                            target.split_once('/').and_then(|(x, y)| {
+                                Some(Command::Follow(x.parse().ok()?, y.parse().ok()?))
+                            })
+                        } else {
+                            cmd.strip_prefix('/').and_then(parse_command)
+                        };
+                        if let Some(command) = command {
+                            command_tx_web.send(command);
+                            request
+                                .respond(Response::empty(StatusCode::from(200)))
+                                .unwrap();
+                        } else {
+                            let response =
+                                Response::from_string("404 Not Found").with_status_code(404);
+                            request.respond(response).unwrap();
+                        }
+                    }
+                    _ => {
                         let response = Response::from_string("404 Not Found").with_status_code(404);
                         request.respond(response).unwrap();
                     }
                 }
-                _ => {
-                    let response = Response::from_string("404 Not Found").with_status_code(404);
-                    request.respond(response).unwrap();
-                }
             }
+        });
+    }
+
+    // Summarize which ways of controlling Pac-Man are available
+    let mut active = Vec::new();
+    if Term::stdout().is_term() {
+        active.push("console".to_string());
+    }
+    active.extend(started_controls(&bind_rx, control_servers, BIND_TIMEOUT));
+    if !active.is_empty() {
+        println!("Control available by {}", active.join(", "));
+    } else if !args.demo && args.patrol.is_empty() {
+        eprintln!("Warning: no control method is available, Pac-Man can't be steered");
+    }
+
+    // Create a connection to the server, a dry run never talks to one
+    let connection = match args.dry_run {
+        Some(_) => None,
+        None => Some(connect_with_retry(
+            &args.url,
+            std::time::Duration::from_secs(args.connect_retry_secs),
+        )?),
+    };
+
+    let framebuffer = args
+        .framebuffer
+        .as_deref()
+        .map(Framebuffer::open)
+        .transpose()?;
+    // An explicit size wins over the framebuffer's, which wins over asking the server
+    let canvas_size = match (args.canvas_size, &framebuffer) {
+        (Some(canvas_size), _) => canvas_size,
+        (None, Some(framebuffer)) => {
+            let (width, height) = framebuffer.size();
+            (
+                width.min(u16::MAX as u32) as u16,
+                height.min(u16::MAX as u32) as u16,
+            )
         }
+        (None, None) => match &connection {
+            Some(connection) => get_canvas_size(connection)?,
+            None => DRY_RUN_CANVAS_SIZE,
+        },
+    };
+
+    if let Some(connection) = connection.as_ref().filter(|_| args.probe) {
+        return probe(
+            connection,
+            args.x.min(canvas_size.0 - 1),
+            args.y.min(canvas_size.1 - 1),
+        );
+    }
+
+    // The shared canvas spans the main server and every additional wall
+    let walls = connect_walls(&args)?;
+    let main_size = canvas_size;
+    let canvas_size = walls.iter().fold(main_size, |(width, height), wall| {
+        (
+            width.max(wall.offset.0.saturating_add(wall.size.0)),
+            height.max(wall.offset.1.saturating_add(wall.size.1)),
+        )
     });
 
-    let gif_decoder =
-        GifDecoder::new(Cursor::new(include_bytes!("pac.gif"))).expect("Failed to decode gif file");
+    if args.size > canvas_size.0.min(canvas_size.1) as u32 {
+        eprintln!(
+            "Sprite size {} exceeds the {}x{} canvas, it will be shrunk to fit",
+            args.size, canvas_size.0, canvas_size.1
+        );
+    }
+    let (right_frames, mut timeline) = load_sprite_frames(&args)?;
+    let mut sprite = SpriteFrames::new(
+        apply_color_options(
+            fit_to_canvas(right_frames, canvas_size, args.filter),
+            &color_options(&args),
+        ),
+        args.max_cached_frames,
+    );
 
-    let right_frames = gif_decoder
-        .into_frames()
-        .collect::<Result<Vec<_>, _>>()
-        .expect("Failed to decode gif into frames")
-        .iter()
-        .map(|frame| {
-            resize(
-                &frame.clone().into_buffer(),
-                pacman_size,
-                pacman_size,
-                image::imageops::FilterType::Nearest,
-            )
-        })
-        .collect::<Vec<_>>();
-    let left_frames = right_frames.iter().map(flip_horizontal).collect::<Vec<_>>();
-    let down_frames = right_frames.iter().map(rotate90).collect::<Vec<_>>();
-    let up_frames = down_frames.iter().map(flip_vertical).collect::<Vec<_>>();
+    // Stay just under an advertised limit, but never above the requested one
+    let detected_limit =
+        connection
+            .as_ref()
+            .and_then(detect_pixel_rate_limit)
+            .map(|(limit, line)| {
+                println!(
+                    "Server advertises a limit of {} pixels per second in its HELP: {:?}",
+                    limit, line
+                );
+                limit
+            });
+    // Only use OFFSET on servers that list it
+    let use_offset = args.use_offset && connection.as_ref().is_none_or(detect_offset_support);
+    if args.use_offset && !use_offset {
+        println!("Server does not list OFFSET in its HELP, sending absolute coordinates");
+    }
+    let max_pps = match (detected_limit.map(|limit| limit / 20 * 19), args.max_pps) {
+        (Some(detected), Some(requested)) => Some(detected.min(requested)),
+        (detected, requested) => detected.or(requested),
+    };
 
-    // Create a connection to the server
-    let connection = TcpStream::connect(&args.url)?;
+    // Pick the decimation level that keeps the sprite refreshing at a steady rate
+    let every_nth_pixel = if let Some(connection) =
+        connection.as_ref().filter(|_| args.auto_optimize)
+    {
+        let measured = calibrate_throughput(
+            connection,
+            &sprite.right[0],
+            Coordinates {
+                x: args.x,
+                y: args.y,
+                bounds: canvas_size,
+            },
+            canvas_size,
+            std::time::Duration::from_secs(2),
+        )?;
+        let throughput = max_pps.map_or(measured, |limit| measured.min(limit as f64));
+        let sprite_pixels = sprite.right[0].width() as f64 * sprite.right[0].height() as f64;
+        let every_nth_pixel =
+            ((sprite_pixels * AUTO_OPTIMIZE_PASSES_PER_SECOND / throughput).ceil() as u64).max(1);
+        println!(
+            "Measured {:.0} pixels per second, sending 1 of every {} pixels per pass",
+            throughput, every_nth_pixel
+        );
+        every_nth_pixel
+    } else {
+        args.every_nth_pixel
+    };
 
-    let canvas_size = get_canvas_size(&connection);
+    let mut readback = match &connection {
+        Some(connection) if args.verify_resend || args.canvas_delta => {
+            Some(readback_reader(connection)?)
+        }
+        _ => None,
+    };
 
-    let mut buff_writer = io::BufWriter::new(connection);
+    let (connection, reconnected): (Box<dyn Write>, _) = match connection {
+        Some(connection) => {
+            let connection = Reconnecting::new(connection, &args.url, main_size);
+            let reconnected = connection.reconnected.clone();
+            // Additional connections share the load of the main one
+            let connection: Box<dyn Write> = if args.connections > 1 {
+                // The connections to one server share its byte budget
+                let max_bps = args.max_bps.div_ceil(args.connections as u64);
+                let mut connections = vec![BinaryPixels::new(
+                    ByteRateLimiter::new(RetryOnWouldBlock::new(connection), max_bps),
+                    args.binary,
+                )];
+                for _ in 1..args.connections {
+                    let extra = connect_with_retry(
+                        &args.url,
+                        std::time::Duration::from_secs(args.connect_retry_secs),
+                    )?;
+                    connections.push(BinaryPixels::new(
+                        ByteRateLimiter::new(
+                            RetryOnWouldBlock::new(Reconnecting::new(extra, &args.url, main_size)),
+                            max_bps,
+                        ),
+                        args.binary,
+                    ));
+                }
+                Box::new(ConnectionPool::new(connections))
+            } else {
+                Box::new(BinaryPixels::new(
+                    ByteRateLimiter::new(RetryOnWouldBlock::new(connection), args.max_bps),
+                    args.binary,
+                ))
+            };
+            (connection, reconnected)
+        }
+        None => (
+            Box::new(BinaryPixels::new(
+                ByteRateLimiter::new(dry_run_output(&args)?, args.max_bps),
+                args.binary,
+            )),
+            Arc::new(Mutex::new(None)),
+        ),
+    };
+    let recording = args.record.as_deref().map(create_recording).transpose()?;
+    #[cfg(unix)]
+    if recording.is_some() {
+        catch_stop_signals();
+    }
+    let mut counter = CountingWriter::new(Tee {
+        inner: Recorder::new(
+            WallRouter {
+                inner: PixelRateLimiter::new(connection, max_pps),
+                inner_size: main_size,
+                walls,
+                pending: Vec::new(),
+            },
+            recording,
+        ),
+        mirror: framebuffer,
+    });
+    counter.limit = args.max_total_pixels;
+    let mut buff_writer = io::BufWriter::new(DeltaFilter::new(
+        counter,
+        canvas_size,
+        args.delta || args.canvas_delta,
+    ));
 
-    let frame_duration = 200;
+    let (relay_tx, relay_rx) = channel::<RelayedPixel>();
+    if let Some(relay_port) = args.relay_port {
+        let _relay_thread = std::thread::spawn(move || {
+            match std::net::TcpListener::bind(("0.0.0.0", relay_port)) {
+                Ok(listener) => serve_relay(listener, canvas_size, relay_tx),
+                Err(e) => eprintln!("Pixelflut relay is unavailable: {}", e),
+            }
+        });
+    }
 
     let mut position = Coordinates {
         x: args.x,
@@ -299,50 +1254,614 @@ fn main() -> Result<(), Box<dyn Error>> {
         bounds: canvas_size,
     };
 
+    let mut render_options = RenderOptions {
+        flush_rows: args.flush_rows,
+        brightness_priority: args.brightness_priority,
+        every_nth_pixel,
+        debug_bbox: args.debug_bbox.then_some(args.debug_bbox_color),
+        tint: None,
+        retry_pixels: args.pixel_retry,
+        strict_bounds: args.strict_bounds,
+        alpha_threshold: args.alpha_threshold,
+        blend_alpha: args.alpha,
+        flash: 0.0,
+        use_offset,
+    };
+    let mut pass: u64 = 0;
+
+    let mut demo = args
+        .demo
+        .then(|| Demo::new(args.demo_features.clone(), args.demo_interval));
+
+    let mut direction_trail = (args.direction_trail
+        || demo
+            .as_ref()
+            .is_some_and(|demo| demo.features.contains(&DemoFeature::Trail)))
+    .then(|| DirectionTrail::new(args.trail_length as usize, args.trail_colors));
+
+    // Waypoints off the canvas could never be reached
+    let on_canvas = |(x, y): (u16, u16)| (x.min(canvas_size.0 - 1), y.min(canvas_size.1 - 1));
+    let mut patrol = (args.patrol.len() == 2).then(|| {
+        Patrol::new(
+            [on_canvas(args.patrol[0]), on_canvas(args.patrol[1])],
+            args.patrol_pause_ms,
+        )
+    });
+
+    let mut swarm = (0..args.swarm as u64)
+        .map(|index| SwarmMember::new(index, canvas_size, args.swarm_color_random))
+        .collect::<Vec<_>>();
+    let mut players = Players::new(canvas_size);
+
     let start_time = std::time::Instant::now();
-    let mut direction = Direction::Right;
+    // Start facing right unless that is not allowed
+    let start_direction = if args.allowed_directions.contains(&Direction::Right) {
+        Direction::Right
+    } else {
+        args.allowed_directions[0]
+    };
+    let mut direction = start_direction;
+    // Turned by a Face command to show other frames than the heading, until Pac-Man is steered
+    let mut turned = Facing::default();
+    // Where Pac-Man is centered on while following the pointer, until he is steered
+    let mut pointer: Option<(u16, u16)> = None;
+    // While paused nothing moves or animates, but the frames are still sent
+    let mut paused = false;
+    let mut last_snapshot: Option<std::time::Instant> = None;
+    let mut last_beat: u128 = 0;
+    let mut last_step = std::time::Instant::now();
+    let mut frame_idx: usize = 0;
+    // What was drawn in the last pass, so a stationary sprite can be verified instead of resent
+    let mut last_drawn: Option<(u16, u16, Direction, usize)> = None;
+    let mut readback_stats = ReadbackStats::default();
+    let mut canvas_delta_checks: u64 = 0;
+    let mut movement_stats = args.movement_stats.then(MovementStats::new);
+    let mut last_heartbeat = std::time::Instant::now();
+    let mut telemetry = args.telemetry.as_deref().map(Telemetry::open).transpose()?;
+    let mut telemetry_pixels = 0;
+    let mut death = SpriteFrames::new(death_frames(&sprite), args.max_cached_frames);
+    let mut pulse = args.pulse.then(|| {
+        Pulse::new(
+            &sprite,
+            args.pulse_amplitude,
+            args.pulse_period_ms,
+            canvas_size,
+            args.max_cached_frames,
+            args.filter,
+        )
+    });
+    // Pac-Man respawns at the start once the death animation is over
+    let mut lifecycle = Lifecycle::new(args.spawn_protect_ms, std::time::Instant::now());
+    // Canvas pixels covered by the last frame, for --erase-trail
+    let mut last_footprint = args.erase_trail.then(HashSet::new);
+    let mut turned_at: Option<std::time::Instant> = None;
+    // Emission orders are computed once per direction and frame
+    let mut pixel_orders: HashMap<(Direction, usize), Vec<u32>> = HashMap::new();
+    let mut hilbert_orders: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+    let mut drawn_frames: Vec<DrawnFrame> = Vec::new();
 
     loop {
-        // Check if there is a new direction
-        if let Ok(new_direction) = direction_rx.try_recv() {
-            direction = new_direction;
+        let previous_direction = direction;
+
+        // Dropping the writers on the way out finishes the recording
+        if stop_requested() {
+            buff_writer.flush()?;
+            println!("Stopped, the recording is complete");
+            return Ok(());
         }
 
-        position = match direction {
-            Direction::Right => {
-                position.x += 1;
-                position
+        // A reconnected server may have lost the canvas, and is read back through the new connection
+        if let Some(connection) = reconnected.lock().unwrap().take() {
+            buff_writer.get_mut().forget_sent();
+            if readback.is_some() {
+                readback = Some(readback_reader(&connection)?);
             }
-            Direction::Left => {
-                position.x -= 1;
-                position
+        }
+
+        for event in player_rx.try_iter() {
+            players.handle(
+                event,
+                &args.allowed_directions,
+                &sprite,
+                &mut buff_writer,
+                &render_options,
+            )?;
+        }
+
+        // Check if there is a new command
+        if let Some(command) = command_queue.try_recv() {
+            match command {
+                Command::Move(new_direction) => {
+                    follow_move_command(
+                        &mut direction,
+                        &mut turned,
+                        &mut pointer,
+                        &mut swarm,
+                        new_direction,
+                        &args.allowed_directions,
+                        args.mirror_control,
+                    );
+                }
+                Command::Face(new_facing) => turned.face(new_facing),
+                Command::Follow(x, y) => pointer = Some((x, y)),
+                Command::Goto(x, y) if x >= canvas_size.0 || y >= canvas_size.1 => eprintln!(
+                    "Ignoring GOTO {} {} outside the {}x{} canvas",
+                    x, y, canvas_size.0, canvas_size.1
+                ),
+                Command::Goto(x, y) => {
+                    position = Coordinates { x, y, ..position };
+                    // Unlike wrapping, the other modes keep the whole sprite on the canvas
+                    if args.boundary != Boundary::Wrap {
+                        let size = sprite.frame(direction, 0).dimensions();
+                        position = position.moved_within(direction, 0, size).0;
+                    }
+                    pointer = None;
+                }
+                Command::Theme(theme) => {
+                    match load_theme(theme, args.size, frame_fit(&args), args.filter) {
+                        Ok((frames, theme_timeline)) => {
+                            timeline = theme_timeline;
+                            sprite = SpriteFrames::new(
+                                apply_color_options(
+                                    fit_to_canvas(frames, canvas_size, args.filter),
+                                    &color_options(&args),
+                                ),
+                                args.max_cached_frames,
+                            );
+                            death =
+                                SpriteFrames::new(death_frames(&sprite), args.max_cached_frames);
+                            pulse = args.pulse.then(|| {
+                                Pulse::new(
+                                    &sprite,
+                                    args.pulse_amplitude,
+                                    args.pulse_period_ms,
+                                    canvas_size,
+                                    args.max_cached_frames,
+                                    args.filter,
+                                )
+                            });
+                            last_drawn = None;
+                            pixel_orders.clear();
+                        }
+                        Err(e) => eprintln!("Failed to load theme {}: {}", theme.name, e),
+                    }
+                }
+                Command::Die => {
+                    if args.death_animation {
+                        lifecycle.die(std::time::Instant::now());
+                    }
+                }
+                Command::Pause => {
+                    paused = !paused;
+                    println!("{}", if paused { "Paused" } else { "Resumed" });
+                }
+                Command::Quit => {
+                    erase_last_frame(&preview, &mut buff_writer, canvas_size, &render_options)?;
+                    buff_writer.flush()?;
+                    println!("Quit, Pac-Man was erased from the canvas");
+                    return Ok(());
+                }
             }
-            Direction::Up => {
-                position.y -= 1;
-                position
+        }
+
+        let current = tunables.lock().unwrap().clone();
+        let frame_duration = current
+            .frame_duration_ms
+            .unwrap_or(DEFAULT_FRAME_DURATION_MS) as u128;
+        // A frame duration set by the user overrides the sprite's own timing
+        let frame_timeline = current.frame_duration_ms.is_none().then_some(&timeline);
+        render_options.tint = current.tint;
+
+        // Let the demo schedule drive Pac-Man through the controls
+        let demo_feature = demo.as_mut().map(|demo| {
+            let (feature, started) = demo.advance();
+            match feature {
+                DemoFeature::Themes if started => {
+                    let theme = &THEMES[demo.stage % THEMES.len()];
+                    command_queue.send(Command::Theme(theme));
+                }
+                DemoFeature::Wander if demo.turn_due() => {
+                    command_queue.send(Command::Move(demo.random_direction()));
+                }
+                _ => {}
             }
-            Direction::Down => {
-                position.y += 1;
-                position
+            feature
+        });
+        let trail_enabled = args.direction_trail || demo_feature == Some(DemoFeature::Trail);
+        let reverse_animation =
+            args.reverse_animation != (demo_feature == Some(DemoFeature::Reverse));
+
+        // Only step on a new beat when synced to a tempo
+        let step = match args.bpm {
+            Some(bpm) => {
+                let beat = start_time.elapsed().as_millis() * bpm as u128 / 60_000;
+                let is_new_beat = beat > last_beat;
+                last_beat = beat;
+                is_new_beat
             }
+            None => true,
         };
 
-        let current_frames = match direction {
-            Direction::Right => &right_frames,
-            Direction::Left => &left_frames,
-            Direction::Up => &up_frames,
-            Direction::Down => &down_frames,
+        // Following the pointer or a patrol steers by itself, and stands still once there or
+        // while dwelling at a waypoint
+        let movement = if step && !lifecycle.is_dying() && !paused {
+            match (pointer, patrol.as_mut()) {
+                (Some(pointer), _) => {
+                    let size = sprite.frame(direction, 0).dimensions();
+                    let target = pointer_target(pointer, canvas_size, size);
+                    step_towards(position, target, current.speed)
+                }
+                (None, Some(patrol)) => {
+                    patrol.advance(position, current.speed, std::time::Instant::now())
+                }
+                (None, None) => Some((direction, current.speed)),
+            }
+        } else {
+            None
         };
+        if let Some((new_direction, distance)) = movement {
+            direction = new_direction;
+            last_step = std::time::Instant::now();
+            if let Some(stats) = movement_stats.as_mut() {
+                stats.record(distance, args.bpm.map(|bpm| bpm as f64 / 60.0));
+            }
+            let size = sprite.frame(direction, 0).dimensions();
+            (position, direction) = args.boundary.step(position, direction, distance, size);
+        }
+        if step && !paused {
+            // Every sprite keeps to the same boundary as Pac-Man
+            let size = sprite.frame(direction, 0).dimensions();
+            for member in &mut swarm {
+                member.step(current.speed, !args.mirror_control, args.boundary, size);
+            }
+            for (member, _) in players.members.values_mut() {
+                member.step(current.speed, false, args.boundary, size);
+            }
+        }
 
-        for _ in 0..10 {
-            let elapsed_time = (std::time::Instant::now() - start_time).as_millis();
-            let frame_idx: usize = (elapsed_time / frame_duration) as usize % current_frames.len();
-            write_frame_to_stream(
-                &current_frames[frame_idx],
-                position,
-                &mut buff_writer,
-                canvas_size,
-            )?;
+        // Which way the shown frames face
+        let facing = turned.of(direction);
+
+        // Flash when turning and fade back to the normal colors over a few frames
+        if args.turn_flash && direction != previous_direction {
+            turned_at = Some(std::time::Instant::now());
+        }
+        let flash_duration = args.turn_flash_frames as u128 * frame_duration;
+        render_options.flash = turned_at.map_or(0.0, |turned_at| {
+            turn_flash(turned_at.elapsed().as_millis(), flash_duration)
+        });
+
+        // Forward relayed pixels first, so Pac-Man is drawn on top of them
+        let sprite_size = sprite.frame(facing, 0).dimensions();
+        forward_relayed_pixels(&relay_rx, position, sprite_size, &mut buff_writer)?;
+
+        if let Some(trail) = direction_trail.as_mut().filter(|_| trail_enabled) {
+            if step {
+                trail.record(position, direction);
+            }
+            trail.write(position, sprite_size, &mut buff_writer, &render_options)?;
+        }
+
+        for _ in 0..current.repeat {
+            // Everything drawn this pass, for snapshots and the preview
+            drawn_frames.clear();
+            // Hold the current frame while the animation is frozen
+            let moving = last_step.elapsed().as_millis() < frame_duration;
+            let frozen = paused
+                || args
+                    .freeze_animation
+                    .is_some_and(|freeze| freeze.freezes(moving));
+            if !frozen {
+                let elapsed_time = (std::time::Instant::now() - start_time).as_millis();
+                frame_idx = frame_index(
+                    elapsed_time,
+                    frame_duration,
+                    frame_timeline,
+                    sprite.len(),
+                    reverse_animation,
+                );
+            }
+            frame_idx %= sprite.len();
+            let now = std::time::Instant::now();
+            let (frame, drawn, frame_position) =
+                match (lifecycle.death_frame(now, frame_duration), &pulse) {
+                    (Some(death_idx), _) => {
+                        let frame = death.frame(facing, death_idx.min(death.len() - 1));
+                        (frame, None, position)
+                    }
+                    (None, Some(pulse)) => {
+                        let base = sprite.frame(facing, frame_idx).dimensions();
+                        let frame = pulse
+                            .level(start_time.elapsed().as_millis())
+                            .frame(facing, frame_idx);
+                        let frame_position = centered(position, base, frame.dimensions());
+                        (frame, None, frame_position)
+                    }
+                    (None, None) => (
+                        sprite.frame(facing, frame_idx),
+                        Some((position.x, position.y, facing, frame_idx)),
+                        position,
+                    ),
+                };
+            // Blink while spawn protected by erasing every other animation frame
+            let (frame, drawn) = if lifecycle.blinked_out(now, frame_duration) {
+                (Cow::Owned(cleared_frame(&frame)), None)
+            } else {
+                (frame, drawn)
+            };
+
+            // Learn what part of the canvas the frame covers already shows, so --canvas-delta
+            // only sends what differs
+            if let Some(reader) = readback.as_mut().filter(|_| args.canvas_delta) {
+                let sample_start = (canvas_delta_checks * args.readback_limit as u64) as usize;
+                let sample = readback_sample(
+                    &frame,
+                    frame_position,
+                    canvas_size,
+                    &render_options,
+                    sample_start..sample_start + args.readback_limit as usize,
+                );
+                let canvas_colors = read_canvas_colors(&mut buff_writer, reader, &sample)?;
+                for (pixel, canvas_color) in sample.iter().zip(canvas_colors) {
+                    buff_writer
+                        .get_mut()
+                        .record_canvas(pixel.point.x, pixel.point.y, canvas_color);
+                }
+                canvas_delta_checks += 1;
+            }
+
+            match &mut readback {
+                Some(reader) if args.verify_resend && drawn.is_some() && drawn == last_drawn => {
+                    let sample_start =
+                        (readback_stats.checks * args.readback_limit as u64) as usize;
+                    let redrawn = redraw_altered_pixels(
+                        &frame,
+                        position,
+                        &mut buff_writer,
+                        reader,
+                        canvas_size,
+                        &render_options,
+                        sample_start..sample_start + args.readback_limit as usize,
+                    )?;
+                    readback_stats.record(redrawn);
+                }
+                _ => {
+                    let order = if args.hilbert_order {
+                        let (width, height) = frame.dimensions();
+                        Some(
+                            hilbert_orders
+                                .entry((width, height))
+                                .or_insert_with(|| hilbert_order(width, height))
+                                .as_slice(),
+                        )
+                    } else {
+                        // Only the visible part of a sprite frame is sent
+                        drawn.is_some().then(|| {
+                            pixel_orders
+                                .entry((facing, frame_idx))
+                                .or_insert_with(|| {
+                                    let order = if args.group_by_color {
+                                        color_grouped_order(&frame)
+                                    } else {
+                                        (0..frame.width() * frame.height()).collect()
+                                    };
+                                    let bounds = sprite.opaque_bounds(facing, frame_idx);
+                                    order
+                                        .into_iter()
+                                        .filter(|&index| {
+                                            bounds.is_some_and(|bounds| {
+                                                bounds.contains(
+                                                    index % frame.width(),
+                                                    index / frame.width(),
+                                                )
+                                            })
+                                        })
+                                        .collect()
+                                })
+                                .as_slice()
+                        })
+                    };
+                    write_frame_to_stream(
+                        &frame,
+                        frame_position,
+                        &mut buff_writer,
+                        canvas_size,
+                        &render_options,
+                        pass,
+                        order,
+                    )?
+                }
+            }
+            drawn_frames.push(DrawnFrame {
+                frame: frame.into_owned(),
+                position: frame_position,
+                tint: render_options.tint,
+            });
+            last_drawn = drawn;
+
+            if let Some(orbit) = &args.orbit {
+                for (index, (center, orbit_direction)) in orbit
+                    .positions(start_time.elapsed())
+                    .into_iter()
+                    .enumerate()
+                {
+                    let orbit_frame =
+                        sprite.frame(orbit_direction, (frame_idx + index) % sprite.len());
+                    let (width, height) = (canvas_size.0 as f32, canvas_size.1 as f32);
+                    let orbit_position = Coordinates {
+                        x: (center.0 - orbit_frame.width() as f32 / 2.0).rem_euclid(width) as u16,
+                        y: (center.1 - orbit_frame.height() as f32 / 2.0).rem_euclid(height) as u16,
+                        bounds: canvas_size,
+                    }
+                    .wrapped();
+                    write_frame_to_stream(
+                        &orbit_frame,
+                        orbit_position,
+                        &mut buff_writer,
+                        canvas_size,
+                        &render_options,
+                        pass,
+                        None,
+                    )?;
+                    drawn_frames.push(DrawnFrame {
+                        frame: orbit_frame.into_owned(),
+                        position: orbit_position,
+                        tint: render_options.tint,
+                    });
+                }
+            }
+
+            let elapsed_time = start_time.elapsed().as_millis();
+            for member in &swarm {
+                let member_idx = frame_index(
+                    elapsed_time + member.phase_ms,
+                    frame_duration,
+                    frame_timeline,
+                    sprite.len(),
+                    reverse_animation,
+                );
+                let member_options = RenderOptions {
+                    tint: member.tint.or(render_options.tint),
+                    ..render_options
+                };
+                let member_frame = sprite.frame(member.direction, member_idx);
+                write_frame_to_stream(
+                    &member_frame,
+                    member.position,
+                    &mut buff_writer,
+                    canvas_size,
+                    &member_options,
+                    pass,
+                    None,
+                )?;
+                drawn_frames.push(DrawnFrame {
+                    frame: member_frame.into_owned(),
+                    position: member.position,
+                    tint: member_options.tint,
+                });
+            }
+            for (member, player_idx) in players.members.values_mut() {
+                *player_idx = frame_index(
+                    elapsed_time + member.phase_ms,
+                    frame_duration,
+                    frame_timeline,
+                    sprite.len(),
+                    reverse_animation,
+                );
+                let player_options = RenderOptions {
+                    tint: member.tint,
+                    ..render_options
+                };
+                let player_frame = sprite.frame(member.direction, *player_idx);
+                write_frame_to_stream(
+                    &player_frame,
+                    member.position,
+                    &mut buff_writer,
+                    canvas_size,
+                    &player_options,
+                    pass,
+                    None,
+                )?;
+                drawn_frames.push(DrawnFrame {
+                    frame: player_frame.into_owned(),
+                    position: member.position,
+                    tint: player_options.tint,
+                });
+            }
+            preview.lock().unwrap().clone_from(&drawn_frames);
+
+            // Black out whatever any sprite covered last pass and none covers now
+            if let Some(previous) = last_footprint.as_mut() {
+                let current: HashSet<(u16, u16)> = drawn_frames
+                    .iter()
+                    .flat_map(|drawn| {
+                        footprint(
+                            &drawn.frame,
+                            drawn.position,
+                            render_options.alpha_threshold,
+                            render_options.debug_bbox.is_some(),
+                        )
+                    })
+                    .collect();
+                for &(x, y) in previous.difference(&current) {
+                    let pixel = Pixel {
+                        point: Coordinates {
+                            x,
+                            y,
+                            bounds: canvas_size,
+                        },
+                        rgb: Color { r: 0, g: 0, b: 0 },
+                        alpha: None,
+                    };
+                    write_pixel(&pixel, &mut buff_writer, &render_options, "Erased trail")?;
+                }
+                *previous = current;
+            }
+
+            if let Some(limit) = args.max_total_pixels {
+                buff_writer.flush()?;
+                if buff_writer.get_ref().inner.limit_reached() {
+                    println!("Sent the budget of {} pixels, stopping", limit);
+                    if args.clear_at_limit {
+                        buff_writer.get_mut().inner.limit = None;
+                        erase_last_frame(&preview, &mut buff_writer, canvas_size, &render_options)?;
+                        buff_writer.flush()?;
+                    }
+                    return Ok(());
+                }
+            }
+            if let Some(telemetry) = telemetry.as_mut() {
+                // Count what actually left for the server, after --delta dropped unchanged pixels
+                buff_writer.flush()?;
+                let pixels = buff_writer.get_ref().inner.pixels;
+                telemetry.record(position, direction, frame_idx, pixels - telemetry_pixels)?;
+                telemetry_pixels = pixels;
+            }
+            metrics
+                .lock()
+                .unwrap()
+                .update(buff_writer.get_ref().inner.pixels, position, direction);
+            pass += 1;
+        }
+
+        // Respawn once the death animation is over
+        if lifecycle.respawn_if_dead(std::time::Instant::now(), frame_duration, death.len()) {
+            direction = start_direction;
+            turned.follow_movement();
+            pointer = None;
+            position = Coordinates {
+                x: args.x,
+                y: args.y,
+                bounds: canvas_size,
+            };
+        }
+
+        if let Some(path) = &args.snapshot {
+            let snapshot_due = last_snapshot.is_none_or(|last| {
+                last.elapsed() >= std::time::Duration::from_secs(args.snapshot_interval)
+            });
+            if snapshot_due {
+                render_scene(&drawn_frames, canvas_size, args.alpha_threshold, args.alpha)
+                    .save_with_format(path, image::ImageFormat::Png)?;
+                last_snapshot = Some(std::time::Instant::now());
+                if args.snapshot_and_exit {
+                    buff_writer.flush()?;
+                    return Ok(());
+                }
+            }
+        }
+
+        if args.heartbeat_secs > 0
+            && last_heartbeat.elapsed() >= std::time::Duration::from_secs(args.heartbeat_secs)
+        {
+            println!(
+                "Heartbeat: at {},{} facing {}, {} frames sent",
+                position.x,
+                position.y,
+                facing.to_possible_value().unwrap().get_name(),
+                pass
+            );
+            last_heartbeat = std::time::Instant::now();
         }
     }
 }
@@ -0,0 +1,916 @@
+//! Ways Pac-Man moves by himself, from the demo schedule to patrols, orbits and swarms
+
+use crate::control::{Command, PlayerEvent};
+use crate::protocol::{Color, Coordinates};
+use crate::render::{clearing_options, write_frame_to_stream, RenderOptions};
+use crate::sprite::{cleared_frame, Direction, SpriteFrames};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// What happens when a sprite reaches the edge of the canvas
+#[derive(Copy, Clone, PartialEq, ValueEnum)]
+pub enum Boundary {
+    // Leave on one side and come back on the other
+    Wrap,
+    // Stop at the edge
+    Clamp,
+    // Turn around at the edge
+    Bounce,
+}
+
+impl Boundary {
+    // Move a sprite of the given size by distance and tell where it ends up and which way it
+    // is heading then
+    pub fn step(
+        self,
+        position: Coordinates,
+        direction: Direction,
+        distance: u16,
+        size: (u32, u32),
+    ) -> (Coordinates, Direction) {
+        match self {
+            Boundary::Wrap => (position.moved(direction, distance), direction),
+            Boundary::Clamp => (
+                position.moved_within(direction, distance, size).0,
+                direction,
+            ),
+            Boundary::Bounce => {
+                let (moved, (off_x, off_y)) = position.moved_within(direction, distance, size);
+                (moved, direction.bounced(off_x, off_y))
+            }
+        }
+    }
+}
+
+/// A showcase behavior that `--demo` rotates through
+#[derive(Copy, Clone, PartialEq, ValueEnum)]
+pub enum DemoFeature {
+    // Change direction at random
+    Wander,
+    // Switch to the next built-in theme
+    Themes,
+    // Leave the direction trail
+    Trail,
+    // Play the animation backwards
+    Reverse,
+}
+
+/// Schedule that activates one demo feature after the other for a fixed interval each
+pub struct Demo {
+    pub features: Vec<DemoFeature>,
+    interval: std::time::Duration,
+    pub stage: usize,
+    stage_start: std::time::Instant,
+    last_turn: std::time::Instant,
+    rng: Xorshift,
+}
+
+impl Demo {
+    pub fn new(features: Vec<DemoFeature>, interval_secs: u64) -> Self {
+        Demo {
+            features,
+            interval: std::time::Duration::from_secs(interval_secs),
+            stage: 0,
+            stage_start: std::time::Instant::now(),
+            last_turn: std::time::Instant::now(),
+            rng: Xorshift::from_clock(0),
+        }
+    }
+
+    // Returns the active feature and whether it just started
+    pub fn advance(&mut self) -> (DemoFeature, bool) {
+        let started = self.stage_start.elapsed() >= self.interval;
+        if started {
+            self.stage += 1;
+            self.stage_start = std::time::Instant::now();
+        }
+        (self.features[self.stage % self.features.len()], started)
+    }
+
+    pub fn turn_due(&mut self) -> bool {
+        let due = self.last_turn.elapsed() >= std::time::Duration::from_secs(1);
+        if due {
+            self.last_turn = std::time::Instant::now();
+        }
+        due
+    }
+
+    pub fn random_direction(&mut self) -> Direction {
+        self.rng.direction()
+    }
+}
+
+/// Xorshift is plenty to make the wandering look unpredictable
+struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    // Seed from the clock, the stream number keeps generators created at the same time apart
+    fn from_clock(stream: u64) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Xorshift {
+            state: (seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn direction(&mut self) -> Direction {
+        [
+            Direction::Right,
+            Direction::Left,
+            Direction::Up,
+            Direction::Down,
+        ][(self.next() % 4) as usize]
+    }
+}
+
+// Chance per step, one in this many, that a swarm member picks a new direction
+const SWARM_TURN_ODDS: u64 = 40;
+
+/// Walks Pac-Man back and forth between two waypoints for `--patrol`
+pub struct Patrol {
+    waypoints: [(u16, u16); 2],
+    pub target: usize,
+    pause: std::time::Duration,
+    dwelling_since: Option<std::time::Instant>,
+}
+
+impl Patrol {
+    pub fn new(waypoints: [(u16, u16); 2], pause_ms: u64) -> Self {
+        Patrol {
+            waypoints,
+            target: 0,
+            pause: std::time::Duration::from_millis(pause_ms),
+            dwelling_since: None,
+        }
+    }
+
+    // The direction and distance of the next step towards the current waypoint, horizontal
+    // first, or None while dwelling at a waypoint
+    pub fn advance(
+        &mut self,
+        position: Coordinates,
+        speed: u16,
+        now: std::time::Instant,
+    ) -> Option<(Direction, u16)> {
+        if let Some(since) = self.dwelling_since {
+            if now.duration_since(since) < self.pause {
+                return None;
+            }
+            self.dwelling_since = None;
+            self.target = 1 - self.target;
+        }
+        let step = step_towards(position, self.waypoints[self.target], speed);
+        if step.is_none() {
+            // Arrived, dwell and then head for the other waypoint
+            self.dwelling_since = Some(now);
+        }
+        step
+    }
+}
+
+// The direction and distance of the next step from a position towards a target, horizontal
+// first, or None once the target is reached
+pub fn step_towards(
+    position: Coordinates,
+    (x, y): (u16, u16),
+    speed: u16,
+) -> Option<(Direction, u16)> {
+    if position.x != x {
+        let direction = if x > position.x {
+            Direction::Right
+        } else {
+            Direction::Left
+        };
+        Some((direction, position.x.abs_diff(x).min(speed)))
+    } else if position.y != y {
+        let direction = if y > position.y {
+            Direction::Down
+        } else {
+            Direction::Up
+        };
+        Some((direction, position.y.abs_diff(y).min(speed)))
+    } else {
+        None
+    }
+}
+
+// Where a sprite of the given size heads for to be centered on the pointer. Pointers off the
+// canvas are clamped onto its edge, like the sprite itself is.
+pub fn pointer_target(
+    (x, y): (u16, u16),
+    canvas_size: (u16, u16),
+    (width, height): (u32, u32),
+) -> (u16, u16) {
+    let x = x.min(canvas_size.0.saturating_sub(1));
+    let y = y.min(canvas_size.1.saturating_sub(1));
+    (
+        x.saturating_sub(width as u16 / 2),
+        y.saturating_sub(height as u16 / 2),
+    )
+}
+
+// Most sprites an orbit can have, each is drawn every pass
+const MAX_ORBIT_SPRITES: usize = 256;
+
+/// Sprites circling a center point for `--orbit`
+#[derive(Clone)]
+pub struct Orbit {
+    pub center: (f32, f32),
+    pub radius: f32,
+    // Degrees per second, positive is clockwise on the canvas
+    pub speed: f32,
+    pub(crate) count: usize,
+}
+
+impl FromStr for Orbit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid orbit '{}': expected X,Y,RADIUS,SPEED,COUNT", s);
+        let parts: Vec<_> = s.split(',').map(str::trim).collect();
+        let [x, y, radius, speed, count] = parts[..] else {
+            return Err(invalid());
+        };
+        let number = |part: &str| part.parse::<f32>().map_err(|_| invalid());
+        let (x, y, radius, speed) = (number(x)?, number(y)?, number(radius)?, number(speed)?);
+        let count = count.parse::<usize>().map_err(|_| invalid())?;
+        if radius < 0.0 || count == 0 {
+            return Err(invalid());
+        }
+        if count > MAX_ORBIT_SPRITES {
+            return Err(format!(
+                "Invalid orbit '{}': at most {} sprites can orbit",
+                s, MAX_ORBIT_SPRITES
+            ));
+        }
+        Ok(Orbit {
+            center: (x, y),
+            radius,
+            speed,
+            count,
+        })
+    }
+}
+
+impl Orbit {
+    // Where each sprite's center is after the elapsed time, and the way it faces along the orbit
+    pub fn positions(&self, elapsed: std::time::Duration) -> Vec<((f32, f32), Direction)> {
+        let rotation = (self.speed * elapsed.as_secs_f32()).to_radians();
+        (0..self.count)
+            .map(|index| {
+                let angle = rotation + std::f32::consts::TAU * index as f32 / self.count as f32;
+                let point = (
+                    self.center.0 + self.radius * angle.cos(),
+                    self.center.1 + self.radius * angle.sin(),
+                );
+                // The tangent, flipped when orbiting the other way round
+                let (dx, dy) = (
+                    -angle.sin() * self.speed.signum(),
+                    angle.cos() * self.speed.signum(),
+                );
+                let direction = match (dx.abs() >= dy.abs(), dx >= 0.0, dy >= 0.0) {
+                    (true, true, _) => Direction::Right,
+                    (true, false, _) => Direction::Left,
+                    (false, _, true) => Direction::Down,
+                    (false, _, false) => Direction::Up,
+                };
+                (point, direction)
+            })
+            .collect()
+    }
+}
+
+/// An autonomous Pac-Man of the `--swarm`
+pub struct SwarmMember {
+    pub position: Coordinates,
+    pub direction: Direction,
+    rng: Xorshift,
+    // Offset into the animation so the swarm does not chomp in lockstep
+    pub phase_ms: u128,
+    pub tint: Option<Color>,
+}
+
+impl SwarmMember {
+    pub fn new(index: u64, canvas_size: (u16, u16), random_color: bool) -> Self {
+        let mut rng = Xorshift::from_clock(index + 1);
+        let position = Coordinates {
+            x: (rng.next() % canvas_size.0 as u64) as u16,
+            y: (rng.next() % canvas_size.1 as u64) as u16,
+            bounds: canvas_size,
+        };
+        let direction = rng.direction();
+        let phase_ms = (rng.next() % 1000) as u128;
+        let tint = random_color.then(|| {
+            let [r, g, b, ..] = rng.next().to_le_bytes();
+            Color { r, g, b }
+        });
+        SwarmMember {
+            position,
+            direction,
+            rng,
+            phase_ms,
+            tint,
+        }
+    }
+
+    // Move on within the boundary, turning at random now and then unless the member is steered
+    // from outside
+    pub fn step(&mut self, speed: u16, wander: bool, boundary: Boundary, size: (u32, u32)) {
+        if wander && self.rng.next().is_multiple_of(SWARM_TURN_ODDS) {
+            self.direction = self.rng.direction();
+        }
+        (self.position, self.direction) = boundary.step(self.position, self.direction, speed, size);
+    }
+}
+
+// Tries at finding a start position no other player has
+const PLAYER_SPAWN_ATTEMPTS: u64 = 16;
+
+/// The Pac-Men of the socket players, with the animation frame each was last drawn with
+pub struct Players {
+    pub members: HashMap<usize, (SwarmMember, usize)>,
+    canvas_size: (u16, u16),
+}
+
+impl Players {
+    pub fn new(canvas_size: (u16, u16)) -> Self {
+        Players {
+            members: HashMap::new(),
+            canvas_size,
+        }
+    }
+
+    // Players only steer their own Pac-Man, and take it off the canvas when they leave
+    pub fn handle<W: Write>(
+        &mut self,
+        event: PlayerEvent,
+        allowed: &[Direction],
+        sprite: &SpriteFrames,
+        buffer: &mut W,
+        options: &RenderOptions,
+    ) -> io::Result<()> {
+        match event {
+            PlayerEvent::Joined(player) => {
+                let member = self.spawn(player);
+                println!("Player {} joined", player);
+                self.members.insert(player, (member, 0));
+            }
+            PlayerEvent::Command(player, Command::Move(new_direction)) => {
+                if let Some((member, _)) = self.members.get_mut(&player) {
+                    steer(
+                        &mut member.direction,
+                        &mut [],
+                        new_direction,
+                        allowed,
+                        false,
+                    );
+                }
+            }
+            PlayerEvent::Command(player, Command::Goto(x, y))
+                if x < self.canvas_size.0 && y < self.canvas_size.1 =>
+            {
+                if let Some((member, _)) = self.members.get_mut(&player) {
+                    member.position = Coordinates {
+                        x,
+                        y,
+                        ..member.position
+                    };
+                }
+            }
+            PlayerEvent::Command(..) => {}
+            PlayerEvent::Left(player) => {
+                if let Some((member, frame_idx)) = self.members.remove(&player) {
+                    let frame = sprite.frame(member.direction, frame_idx);
+                    write_frame_to_stream(
+                        &cleared_frame(&frame),
+                        member.position,
+                        buffer,
+                        self.canvas_size,
+                        &clearing_options(options),
+                        0,
+                        None,
+                    )?;
+                    println!("Player {} left", player);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // A new Pac-Man that doesn't start where another player's is
+    fn spawn(&self, player: usize) -> SwarmMember {
+        let taken = |member: &SwarmMember| {
+            self.members.values().any(|(other, _)| {
+                (other.position.x, other.position.y) == (member.position.x, member.position.y)
+            })
+        };
+        let stream = player as u64 * PLAYER_SPAWN_ATTEMPTS;
+        (stream..stream + PLAYER_SPAWN_ATTEMPTS)
+            .map(|stream| SwarmMember::new(stream + 1, self.canvas_size, true))
+            .find(|member| !taken(member))
+            .unwrap_or_else(|| SwarmMember::new(stream + 1, self.canvas_size, true))
+    }
+}
+
+// Turn Pac-Man, and with mirrored control every swarm member along with him, unless the
+// direction isn't allowed. Tells whether the command was followed.
+pub fn steer(
+    direction: &mut Direction,
+    swarm: &mut [SwarmMember],
+    new_direction: Direction,
+    allowed: &[Direction],
+    mirror: bool,
+) -> bool {
+    if !allowed.contains(&new_direction) {
+        return false;
+    }
+    *direction = new_direction;
+    if mirror {
+        for member in swarm {
+            member.direction = new_direction;
+        }
+    }
+    true
+}
+
+/// Which way the sprite looks, the way it moves unless a FACE command turned it elsewhere
+#[derive(Default)]
+pub struct Facing {
+    turned: Option<Direction>,
+}
+
+impl Facing {
+    pub fn face(&mut self, direction: Direction) {
+        self.turned = Some(direction);
+    }
+
+    // Look where he is going again, e.g. once he is steered
+    pub fn follow_movement(&mut self) {
+        self.turned = None;
+    }
+
+    pub fn of(&self, moving: Direction) -> Direction {
+        self.turned.unwrap_or(moving)
+    }
+}
+
+// Steer Pac-Man for a MOVE command. Once he turns he looks where he is going again and stops
+// following the pointer. Tells whether the command was followed.
+pub fn follow_move_command(
+    direction: &mut Direction,
+    facing: &mut Facing,
+    pointer: &mut Option<(u16, u16)>,
+    swarm: &mut [SwarmMember],
+    new_direction: Direction,
+    allowed: &[Direction],
+    mirror: bool,
+) -> bool {
+    let followed = steer(direction, swarm, new_direction, allowed, mirror);
+    if followed {
+        facing.follow_movement();
+        *pointer = None;
+    }
+    followed
+}
+
+/// Pac-Man's death and respawn for `--death-animation`, and the `--spawn-protect-ms` window
+/// after each spawn in which he can't die
+pub struct Lifecycle {
+    // Set while the death animation plays
+    dying_since: Option<std::time::Instant>,
+    spawned_at: std::time::Instant,
+    protection: std::time::Duration,
+}
+
+impl Lifecycle {
+    pub fn new(protect_ms: u64, now: std::time::Instant) -> Self {
+        Lifecycle {
+            dying_since: None,
+            spawned_at: now,
+            protection: std::time::Duration::from_millis(protect_ms),
+        }
+    }
+
+    pub fn is_dying(&self) -> bool {
+        self.dying_since.is_some()
+    }
+
+    pub fn is_protected(&self, now: std::time::Instant) -> bool {
+        now.saturating_duration_since(self.spawned_at) < self.protection
+    }
+
+    // Start the death animation unless it already plays or Pac-Man is spawn protected, and
+    // tell whether it started
+    pub fn die(&mut self, now: std::time::Instant) -> bool {
+        let dies = !self.is_dying() && !self.is_protected(now);
+        if dies {
+            self.dying_since = Some(now);
+        }
+        dies
+    }
+
+    // Which death animation frame is shown, None while alive
+    pub fn death_frame(&self, now: std::time::Instant, frame_duration: u128) -> Option<usize> {
+        self.dying_since.map(|since| {
+            (now.saturating_duration_since(since).as_millis() / frame_duration) as usize
+        })
+    }
+
+    // Respawn once all frames of the death animation were shown, and tell whether he did
+    pub fn respawn_if_dead(
+        &mut self,
+        now: std::time::Instant,
+        frame_duration: u128,
+        frame_count: usize,
+    ) -> bool {
+        let dead = self
+            .death_frame(now, frame_duration)
+            .is_some_and(|frame| frame >= frame_count);
+        if dead {
+            self.dying_since = None;
+            self.spawned_at = now;
+        }
+        dead
+    }
+
+    // While protected the sprite blinks, hidden for every other animation frame
+    pub fn blinked_out(&self, now: std::time::Instant, frame_duration: u128) -> bool {
+        let since_spawn = now.saturating_duration_since(self.spawned_at).as_millis();
+        self.is_protected(now) && (since_spawn / frame_duration) % 2 == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disallowed_directions_are_ignored() {
+        let allowed = [Direction::Left, Direction::Right];
+        let mut direction = Direction::Right;
+        for ignored in [Direction::Up, Direction::Down, Direction::UpLeft] {
+            assert!(!steer(&mut direction, &mut [], ignored, &allowed, false));
+        }
+        assert!(direction == Direction::Right);
+        assert!(steer(
+            &mut direction,
+            &mut [],
+            Direction::Left,
+            &allowed,
+            false
+        ));
+        assert!(direction == Direction::Left);
+        assert!(steer(
+            &mut direction,
+            &mut [],
+            Direction::Right,
+            &allowed,
+            false
+        ));
+        assert!(direction == Direction::Right);
+    }
+
+    #[test]
+    fn clamping_stops_at_each_edge() {
+        let at = |x, y| Coordinates {
+            x,
+            y,
+            bounds: (20, 10),
+        };
+        for (start, direction, end) in [
+            (at(1, 1), Direction::Left, (0, 1)),
+            (at(1, 1), Direction::Up, (1, 0)),
+            (at(15, 1), Direction::Right, (16, 1)),
+            (at(1, 5), Direction::Down, (1, 6)),
+            (at(15, 5), Direction::DownRight, (16, 6)),
+        ] {
+            let (moved, heading) = Boundary::Clamp.step(start, direction, 3, (4, 4));
+            assert_eq!((moved.x, moved.y), end);
+            assert!(heading == direction);
+        }
+    }
+
+    #[test]
+    fn bouncing_turns_around_and_stays_on_the_canvas() {
+        let at = |x, y| Coordinates {
+            x,
+            y,
+            bounds: (20, 10),
+        };
+        let (moved, heading) = Boundary::Bounce.step(at(15, 1), Direction::Right, 3, (4, 4));
+        assert_eq!((moved.x, moved.y), (16, 1));
+        assert!(heading == Direction::Left);
+        // Into a corner both ways turn around, along a wall only one of them
+        let (moved, heading) = Boundary::Bounce.step(at(15, 5), Direction::DownRight, 3, (4, 4));
+        assert_eq!((moved.x, moved.y), (16, 6));
+        assert!(heading == Direction::UpLeft);
+        let (_, heading) = Boundary::Bounce.step(at(5, 1), Direction::UpLeft, 3, (4, 4));
+        assert!(heading == Direction::DownLeft);
+
+        // Wandering swarm members keep to the boundary too
+        let mut member = SwarmMember::new(1, (20, 10), false);
+        member.position = at(0, 0);
+        for _ in 0..500 {
+            member.step(3, true, Boundary::Bounce, (4, 4));
+            assert!(member.position.x <= 16 && member.position.y <= 6);
+        }
+    }
+
+    #[test]
+    fn leaving_players_are_cleared_where_they_were_last_drawn() {
+        let sprite = SpriteFrames::new(
+            vec![image::RgbaImage::from_pixel(
+                2,
+                1,
+                image::Rgba([255, 255, 0, 255]),
+            )],
+            None,
+        );
+        // Decimated drawing still clears the whole footprint at once
+        let options = RenderOptions {
+            flush_rows: None,
+            brightness_priority: false,
+            every_nth_pixel: 2,
+            debug_bbox: None,
+            tint: None,
+            retry_pixels: false,
+            strict_bounds: false,
+            alpha_threshold: 0,
+            blend_alpha: false,
+            flash: 0.0,
+            use_offset: false,
+        };
+        let allowed = [Direction::Right, Direction::Down];
+        let mut players = Players::new((100, 50));
+        let mut sent = Vec::new();
+        let mut handle = |players: &mut Players, event| {
+            players
+                .handle(event, &allowed, &sprite, &mut sent, &options)
+                .unwrap();
+        };
+        for player in 0..8 {
+            handle(&mut players, PlayerEvent::Joined(player));
+        }
+        let starts: std::collections::HashSet<_> = players
+            .members
+            .values()
+            .map(|(member, _)| (member.position.x, member.position.y))
+            .collect();
+        assert_eq!(starts.len(), 8);
+
+        handle(&mut players, PlayerEvent::Command(3, Command::Goto(10, 20)));
+        handle(
+            &mut players,
+            PlayerEvent::Command(3, Command::Move(Direction::Right)),
+        );
+        // Commands of players that aren't there, or outside the canvas, are ignored
+        handle(&mut players, PlayerEvent::Command(9, Command::Goto(1, 1)));
+        handle(&mut players, PlayerEvent::Command(3, Command::Goto(100, 1)));
+        handle(&mut players, PlayerEvent::Left(3));
+        handle(&mut players, PlayerEvent::Left(3));
+        assert_eq!(players.members.len(), 7);
+        assert_eq!(
+            String::from_utf8(sent).unwrap(),
+            "PX 10 20 000000\nPX 11 20 000000\n"
+        );
+    }
+
+    #[test]
+    fn orbits_need_a_whole_and_bounded_sprite_count() {
+        let orbit: Orbit = "50, 25, 10, -90, 3".parse().unwrap();
+        assert_eq!(
+            (orbit.center, orbit.radius, orbit.speed),
+            ((50.0, 25.0), 10.0, -90.0)
+        );
+        assert_eq!(orbit.count, 3);
+        assert_eq!(orbit.positions(std::time::Duration::ZERO).len(), 3);
+        let most = format!("0,0,1,1,{}", MAX_ORBIT_SPRITES);
+        assert_eq!(most.parse::<Orbit>().unwrap().count, MAX_ORBIT_SPRITES);
+        for invalid in [
+            "0,0,1,1,0",
+            "0,0,1,1,2.5",
+            "0,0,1,1,1e9",
+            "0,0,1,1,257",
+            "0,0,1,1,-1",
+            "0,0,-1,1,3",
+            "0,0,1,1",
+        ] {
+            assert!(invalid.parse::<Orbit>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn mirrored_control_moves_every_swarm_member_in_unison() {
+        let canvas_size = (100, 50);
+        let mut swarm: Vec<_> = (0..5)
+            .map(|index| SwarmMember::new(index, canvas_size, false))
+            .collect();
+        let mut direction = Direction::Right;
+        let allowed = [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ];
+        assert!(steer(
+            &mut direction,
+            &mut swarm,
+            Direction::Up,
+            &allowed,
+            true
+        ));
+        let before: Vec<_> = swarm.iter().map(|member| member.position).collect();
+        for member in &mut swarm {
+            // Mirrored members don't wander off on their own
+            member.step(2, false, Boundary::Wrap, (4, 4));
+        }
+        for (member, before) in swarm.iter().zip(before) {
+            assert!(member.direction == Direction::Up);
+            let expected = before.moved(Direction::Up, 2);
+            assert_eq!(
+                (member.position.x, member.position.y),
+                (expected.x, expected.y)
+            );
+        }
+
+        // Without mirroring only Pac-Man is steered
+        assert!(steer(
+            &mut direction,
+            &mut swarm,
+            Direction::Down,
+            &allowed,
+            false
+        ));
+        assert!(direction == Direction::Down);
+        assert!(swarm.iter().all(|member| member.direction == Direction::Up));
+    }
+
+    #[test]
+    fn facing_can_differ_from_the_movement() {
+        let allowed = [Direction::Right, Direction::Down];
+        let mut direction = Direction::Right;
+        let mut facing = Facing::default();
+        let mut pointer = Some((40, 40));
+        assert!(facing.of(direction) == Direction::Right);
+        facing.face(Direction::Up);
+        let position = Coordinates {
+            x: 10,
+            y: 10,
+            bounds: (100, 50),
+        }
+        .moved(direction, 3);
+        // Still moving right while looking up
+        assert_eq!((position.x, position.y), (13, 10));
+        assert!(facing.of(direction) == Direction::Up);
+
+        // A direction that isn't allowed changes nothing
+        assert!(!follow_move_command(
+            &mut direction,
+            &mut facing,
+            &mut pointer,
+            &mut [],
+            Direction::Left,
+            &allowed,
+            false,
+        ));
+        assert!(facing.of(direction) == Direction::Up);
+        assert_eq!(pointer, Some((40, 40)));
+        // Turning makes him look where he goes again and stop following the pointer
+        assert!(follow_move_command(
+            &mut direction,
+            &mut facing,
+            &mut pointer,
+            &mut [],
+            Direction::Down,
+            &allowed,
+            false,
+        ));
+        assert!(facing.of(direction) == Direction::Down);
+        assert_eq!(pointer, None);
+    }
+
+    fn after(start: std::time::Instant, ms: u64) -> std::time::Instant {
+        start + std::time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn patrols_dwell_at_each_end_and_then_turn_around() {
+        let start = std::time::Instant::now();
+        let mut patrol = Patrol::new([(20, 10), (10, 10)], 300);
+        let mut position = Coordinates {
+            x: 16,
+            y: 10,
+            bounds: (100, 50),
+        };
+        let mut direction = Direction::Up;
+        let facing = Facing::default();
+        let mut ms = 0;
+        while let Some((new_direction, distance)) = patrol.advance(position, 3, after(start, ms)) {
+            direction = new_direction;
+            position = position.moved(direction, distance);
+            ms += 10;
+        }
+        assert_eq!((position.x, position.y), (20, 10));
+        assert!(facing.of(direction) == Direction::Right);
+
+        // He holds still at the waypoint for the whole pause
+        let arrived = ms;
+        for ms in [arrived + 10, arrived + 150, arrived + 299] {
+            assert!(patrol.advance(position, 3, after(start, ms)).is_none());
+        }
+        assert_eq!(patrol.target, 0);
+        // Then heads back to the other waypoint, facing the way he now goes
+        let (new_direction, distance) = patrol
+            .advance(position, 3, after(start, arrived + 300))
+            .unwrap();
+        direction = new_direction;
+        assert_eq!(patrol.target, 1);
+        assert_eq!(distance, 3);
+        assert!(facing.of(direction) == Direction::Left);
+    }
+
+    #[test]
+    fn dying_plays_the_death_animation_once_and_respawns() {
+        let start = std::time::Instant::now();
+        let mut lifecycle = Lifecycle::new(0, start);
+        assert_eq!(lifecycle.death_frame(start, 100), None);
+        assert!(lifecycle.die(after(start, 1000)));
+        assert!(lifecycle.is_dying());
+        // Dying again while the animation plays changes nothing
+        assert!(!lifecycle.die(after(start, 1150)));
+        assert_eq!(lifecycle.death_frame(after(start, 1250), 100), Some(2));
+        assert!(!lifecycle.respawn_if_dead(after(start, 1399), 100, 4));
+        assert!(lifecycle.respawn_if_dead(after(start, 1400), 100, 4));
+        assert!(!lifecycle.is_dying());
+        assert_eq!(lifecycle.death_frame(after(start, 1400), 100), None);
+        // And he can die again
+        assert!(lifecycle.die(after(start, 2000)));
+    }
+
+    #[test]
+    fn spawn_protection_ignores_deaths_and_blinks() {
+        let start = std::time::Instant::now();
+        let mut lifecycle = Lifecycle::new(500, start);
+        assert!(lifecycle.is_protected(after(start, 100)));
+        assert!(!lifecycle.die(after(start, 100)));
+        assert!(!lifecycle.is_dying());
+        // Hidden every other frame while protected, always shown afterwards
+        let blinks: Vec<_> = [0, 100, 200, 300, 450, 500, 600, 700]
+            .into_iter()
+            .map(|ms| lifecycle.blinked_out(after(start, ms), 100))
+            .collect();
+        assert_eq!(
+            blinks,
+            [false, true, false, true, false, false, false, false]
+        );
+        assert!(lifecycle.die(after(start, 600)));
+
+        // Respawning protects him again
+        assert!(lifecycle.respawn_if_dead(after(start, 1000), 100, 4));
+        assert!(!lifecycle.die(after(start, 1200)));
+        assert!(lifecycle.blinked_out(after(start, 1100), 100));
+        assert!(lifecycle.die(after(start, 1500)));
+    }
+
+    // Steps a 4x4 sprite at the given speed until it reaches the target for the pointer
+    fn follow(position: Coordinates, pointer: (u16, u16), speed: u16) -> Coordinates {
+        let target = pointer_target(pointer, position.bounds, (4, 4));
+        let mut position = position;
+        while let Some((direction, distance)) = step_towards(position, target, speed) {
+            assert!(distance <= speed);
+            position = position.moved(direction, distance);
+        }
+        position
+    }
+
+    #[test]
+    fn following_steers_towards_each_new_pointer_position() {
+        let mut position = Coordinates {
+            x: 0,
+            y: 0,
+            bounds: (100, 50),
+        };
+        for (pointer, expected) in [((20, 10), (18, 8)), ((5, 40), (3, 38)), ((1, 1), (0, 0))] {
+            position = follow(position, pointer, 3);
+            assert_eq!((position.x, position.y), expected);
+        }
+    }
+
+    #[test]
+    fn off_canvas_pointers_are_clamped_onto_the_canvas() {
+        assert_eq!(pointer_target((500, 20), (100, 50), (4, 4)), (97, 18));
+        assert_eq!(pointer_target((10, u16::MAX), (100, 50), (4, 4)), (8, 47));
+    }
+}
@@ -0,0 +1,824 @@
+//! Pixelflut commands and canvas coordinates, and talking to a server
+
+use crate::sprite::Direction;
+use image::Rgba;
+use std::io::{self, BufRead, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+
+/// A point on the canvas, `bounds` is the canvas size that positions wrap around
+#[derive(Copy, Clone)]
+pub struct Coordinates {
+    pub x: u16,
+    pub y: u16,
+    pub bounds: (u16, u16),
+}
+
+impl std::ops::Add<Coordinates> for Coordinates {
+    type Output = Coordinates;
+
+    fn add(self, other: Coordinates) -> Coordinates {
+        // Sum in u32, large canvases would overflow u16
+        let (width, height) = (self.bounds.0 as u32, self.bounds.1 as u32);
+        Coordinates {
+            x: ((self.x as u32 + other.x as u32 + width) % width) as u16,
+            y: ((self.y as u32 + other.y as u32 + height) % height) as u16,
+            bounds: self.bounds,
+        }
+    }
+}
+
+impl Coordinates {
+    // Bring the coordinates back onto the canvas
+    pub fn wrapped(self) -> Coordinates {
+        self + Coordinates {
+            x: 0,
+            y: 0,
+            bounds: self.bounds,
+        }
+    }
+
+    // Move by distance in the given direction, wrapping around the canvas edges
+    pub fn moved(self, direction: Direction, distance: u16) -> Coordinates {
+        let (width, height) = self.bounds;
+        let (distance_x, distance_y) = (distance % width, distance % height);
+        // Moving back is moving forward by the rest of the canvas
+        let x = match direction {
+            Direction::Right | Direction::UpRight | Direction::DownRight => distance_x,
+            Direction::Left | Direction::UpLeft | Direction::DownLeft => width - distance_x,
+            Direction::Up | Direction::Down => 0,
+        };
+        let y = match direction {
+            Direction::Down | Direction::DownLeft | Direction::DownRight => distance_y,
+            Direction::Up | Direction::UpLeft | Direction::UpRight => height - distance_y,
+            Direction::Left | Direction::Right => 0,
+        };
+        self + Coordinates {
+            x,
+            y,
+            bounds: self.bounds,
+        }
+    }
+
+    // Move by distance in the given direction, but keep a box of the given size starting here
+    // on the canvas. Also tells whether the move ran into a vertical or horizontal edge.
+    pub fn moved_within(
+        self,
+        direction: Direction,
+        distance: u16,
+        size: (u32, u32),
+    ) -> (Coordinates, (bool, bool)) {
+        let (step_x, step_y) = direction.steps();
+        let max_x = (self.bounds.0 as u32).saturating_sub(size.0) as i32;
+        let max_y = (self.bounds.1 as u32).saturating_sub(size.1) as i32;
+        let x = self.x as i32 + step_x * distance as i32;
+        let y = self.y as i32 + step_y * distance as i32;
+        let moved = Coordinates {
+            x: x.clamp(0, max_x) as u16,
+            y: y.clamp(0, max_y) as u16,
+            bounds: self.bounds,
+        };
+        (moved, (moved.x as i32 != x, moved.y as i32 != y))
+    }
+
+    // Whether a canvas point lies in the box of the given size starting here, wrapping at the edges
+    pub fn box_contains(self, size: (u32, u32), x: u16, y: u16) -> bool {
+        let origin = self.wrapped();
+        let (width, height) = (self.bounds.0 as u32, self.bounds.1 as u32);
+        let dx = (x as u32 + width - origin.x as u32) % width;
+        let dy = (y as u32 + height - origin.y as u32) % height;
+        dx < size.0 && dy < size.1
+    }
+}
+
+/// An RGB color, written as `RRGGBB` in pixel commands
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl From<Rgba<u8>> for Color {
+    fn from(rgba: Rgba<u8>) -> Self {
+        Color {
+            r: rgba[0],
+            g: rgba[1],
+            b: rgba[2],
+        }
+    }
+}
+
+impl Color {
+    // Perceived brightness using the Rec. 601 luma weights
+    pub(crate) fn luminance(&self) -> u8 {
+        ((299 * self.r as u32 + 587 * self.g as u32 + 114 * self.b as u32) / 1000) as u8
+    }
+
+    // Fade the color towards black, a factor of 0.0 is black and 1.0 the original color
+    pub(crate) fn scaled(&self, factor: f32) -> Color {
+        Color {
+            r: (self.r as f32 * factor) as u8,
+            g: (self.g as f32 * factor) as u8,
+            b: (self.b as f32 * factor) as u8,
+        }
+    }
+
+    // Mix in the other color, an amount of 0 leaves the color unchanged and 1 replaces it
+    pub fn blended(&self, other: Color, amount: f32) -> Color {
+        let mix = |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * amount) as u8;
+        Color {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+        }
+    }
+
+    // Multiply every channel with the tint, white leaves the color unchanged
+    pub(crate) fn tinted(&self, tint: Color) -> Color {
+        Color {
+            r: (self.r as u16 * tint.r as u16 / 255) as u8,
+            g: (self.g as u16 * tint.g as u16 / 255) as u8,
+            b: (self.b as u16 * tint.b as u16 / 255) as u8,
+        }
+    }
+
+    // Raise the luminance to at least the floor, scaling the channels up proportionally and
+    // mixing in white where a channel would exceed 255 or the color has no brightness to scale
+    pub fn brightened(&self, floor: u8) -> Color {
+        let luminance = self.luminance();
+        if luminance >= floor {
+            return *self;
+        }
+        let scaled = if luminance > 0 {
+            self.scaled(floor as f32 / luminance as f32)
+        } else {
+            *self
+        };
+        let luminance = scaled.luminance();
+        if luminance >= floor {
+            return scaled;
+        }
+        // Round up so truncation never leaves the color just below the floor
+        let amount = (floor - luminance) as f32 / (255 - luminance) as f32;
+        let whiten =
+            |channel: u8| (channel as f32 + (255.0 - channel as f32) * amount).ceil() as u8;
+        Color {
+            r: whiten(scaled.r),
+            g: whiten(scaled.g),
+            b: whiten(scaled.b),
+        }
+    }
+
+    pub fn grayscale(&self) -> Color {
+        let luminance = self.luminance();
+        Color {
+            r: luminance,
+            g: luminance,
+            b: luminance,
+        }
+    }
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.trim_start_matches('#');
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or_else(|| format!("Invalid color '{}': expected RRGGBB", s))
+        };
+        if hex.len() != 6 {
+            return Err(format!("Invalid color '{}': expected RRGGBB", s));
+        }
+        Ok(Color {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+}
+
+/// One pixel command for the canvas
+pub struct Pixel {
+    pub point: Coordinates,
+    pub rgb: Color,
+    // Sent as `rrggbbaa` for servers that blend, opaque pixels leave it out
+    pub alpha: Option<u8>,
+}
+
+// Lowercase hex digits of every byte value, so colors skip the formatter for every pixel
+const HEX_BYTES: [[u8; 2]; 256] = {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut table = [[0; 2]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = [DIGITS[byte >> 4], DIGITS[byte & 0xf]];
+        byte += 1;
+    }
+    table
+};
+
+// Write the decimal digits of value to the start of out and return how many there are
+fn write_decimal(out: &mut [u8], value: u16) -> usize {
+    let mut digits = [0; 5];
+    let (mut rest, mut count) = (value, 0);
+    loop {
+        digits[digits.len() - 1 - count] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        count += 1;
+        if rest == 0 {
+            break;
+        }
+    }
+    out[..count].copy_from_slice(&digits[digits.len() - count..]);
+    count
+}
+
+impl Pixel {
+    // Send the pixel as a `PX x y rrggbb[aa]` command. The command is assembled on the stack and
+    // written at once, this runs for every pixel of every pass.
+    pub fn write<T: Write>(&self, buffer: &mut T) -> io::Result<()> {
+        // Long enough for "PX 65535 65535 rrggbbaa\n"
+        let mut line = [0; 24];
+        line[..3].copy_from_slice(b"PX ");
+        let mut len = 3;
+        for coordinate in [self.point.x, self.point.y] {
+            len += write_decimal(&mut line[len..], coordinate);
+            line[len] = b' ';
+            len += 1;
+        }
+        for byte in [self.rgb.r, self.rgb.g, self.rgb.b]
+            .into_iter()
+            .chain(self.alpha)
+        {
+            line[len..len + 2].copy_from_slice(&HEX_BYTES[byte as usize]);
+            len += 2;
+        }
+        line[len] = b'\n';
+        buffer.write_all(&line[..=len])
+    }
+}
+
+// Whether a write failed because the server dropped the connection
+pub(crate) fn is_disconnect_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WriteZero
+    )
+}
+
+// Append buf to the pending bytes and take out every complete command, leaving a partial
+// command at the end pending for the next write
+pub(crate) fn complete_commands(pending: &mut Vec<u8>, buf: &[u8]) -> Vec<u8> {
+    pending.extend_from_slice(buf);
+    let complete = pending
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |end| end + 1);
+    pending.drain(..complete).collect()
+}
+
+// Whether a command sets a pixel, as opposed to reading one with `PX x y` or any other command
+pub(crate) fn is_pixel_write(command: &[u8]) -> bool {
+    command.starts_with(b"PX ") && command.trim_ascii_end().split(|&b| b == b' ').count() == 4
+}
+
+// The coordinates and color of a `PX x y color` command, None for any other command
+pub(crate) fn parse_pixel_command(command: &[u8]) -> Option<(u16, u16, &str)> {
+    let text = std::str::from_utf8(command).ok()?;
+    let mut parts = text.split_whitespace();
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("PX"), Some(x), Some(y), Some(color)) => {
+            Some((x.parse().ok()?, y.parse().ok()?, color))
+        }
+        _ => None,
+    }
+}
+
+// The binary `PB` frame for a pixel
+pub(crate) fn binary_pixel(x: u16, y: u16, color: Color, alpha: u8) -> [u8; 10] {
+    let [x_low, x_high] = x.to_le_bytes();
+    let [y_low, y_high] = y.to_le_bytes();
+    [
+        b'P', b'B', x_low, x_high, y_low, y_high, color.r, color.g, color.b, alpha,
+    ]
+}
+
+// How long the server gets to answer `SIZE`
+const CANVAS_SIZE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Lines of banners and prompts that are skipped while waiting for the `SIZE` answer
+const MAX_CANVAS_SIZE_LINES: usize = 16;
+
+/// Ask the server for its canvas size with `SIZE`, failing if it doesn't answer in time
+pub fn get_canvas_size(mut stream: &TcpStream) -> io::Result<(u16, u16)> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    stream.set_read_timeout(Some(CANVAS_SIZE_TIMEOUT))?;
+    stream.write_all(b"SIZE\n")?;
+    let size = read_canvas_size(&mut reader);
+    stream.set_read_timeout(None)?;
+    size
+}
+
+// Find the `SIZE <width> <height>` answer, anywhere in a line so prompts around it don't matter
+fn read_canvas_size<R: BufRead>(reader: &mut R) -> io::Result<(u16, u16)> {
+    let mut last_line = String::new();
+    for _ in 0..MAX_CANVAS_SIZE_LINES {
+        let mut buffer = String::new();
+        match reader.read_line(&mut buffer) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The server closed the connection before sending its size",
+                ))
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "The server did not send its size within {}s",
+                        CANVAS_SIZE_TIMEOUT.as_secs()
+                    ),
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+        let mut parts = buffer
+            .split_whitespace()
+            .skip_while(|part| !part.eq_ignore_ascii_case("SIZE"))
+            .skip(1);
+        let width = parts.next().and_then(|f| f.parse::<u16>().ok());
+        let height = parts.next().and_then(|f| f.parse::<u16>().ok());
+        if let (Some(width), Some(height)) = (width, height) {
+            // Positions wrap around the canvas, an empty one has nothing to wrap around
+            if width == 0 || height == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The server reported an empty {}x{} canvas", width, height),
+                ));
+            }
+            return Ok((width, height));
+        }
+        last_line = buffer;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Failed parsing of size response: {:?}", last_line.trim()),
+    ))
+}
+
+// Ask for the server's HELP text, reading lines until it goes quiet for half a second
+fn read_help(mut stream: &TcpStream) -> Option<Vec<String>> {
+    let reader = io::BufReader::new(stream.try_clone().ok()?);
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_millis(500)))
+        .ok()?;
+    stream.write_all(b"HELP\n").ok()?;
+    let lines = reader.lines().map_while(Result::ok).collect();
+    stream.set_read_timeout(None).ok()?;
+    Some(lines)
+}
+
+// Look for a pixel rate limit in the server's HELP text, e.g. "LIMIT 10000" or "rate: 10000 px/s".
+// Returns the limit and the line it was taken from.
+pub fn detect_pixel_rate_limit(stream: &TcpStream) -> Option<(u32, String)> {
+    pixel_rate_limit(read_help(stream)?)
+}
+
+// The first number of the first line about a limit or rate that has a usable one, lines with a
+// number too large for a limit are skipped rather than read from further on
+fn pixel_rate_limit(help: Vec<String>) -> Option<(u32, String)> {
+    help.into_iter()
+        .filter(|line| {
+            let line = line.to_lowercase();
+            line.contains("limit") || line.contains("rate")
+        })
+        .find_map(|line| {
+            let number = line
+                .split(|c: char| !c.is_ascii_digit())
+                .find(|number| !number.is_empty())?;
+            let limit = number.parse::<u32>().ok().filter(|&limit| limit > 0)?;
+            Some((limit, line.trim().to_string()))
+        })
+}
+
+/// Whether the server's HELP text mentions `OFFSET`, so pixel commands can be sent relative to it
+pub fn detect_offset_support(stream: &TcpStream) -> bool {
+    read_help(stream).is_some_and(|lines| {
+        lines
+            .iter()
+            .any(|line| line.to_lowercase().contains("offset"))
+    })
+}
+
+// Read one canvas pixel, None if the server does not answer in time or reads are unsupported
+pub fn read_pixel(
+    mut stream: &TcpStream,
+    reader: &mut impl BufRead,
+    x: u16,
+    y: u16,
+) -> io::Result<Option<Color>> {
+    writeln!(stream, "PX {} {}", x, y)?;
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(_) => {}
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(line
+        .split_whitespace()
+        .nth(3)
+        .and_then(|color| color.get(..6))
+        .and_then(|color| color.parse().ok()))
+}
+
+// Port used when the server address doesn't name one
+const DEFAULT_PORT: u16 = 1234;
+
+/// Split a server address into host and port. IPv6 addresses go in brackets when a port follows,
+/// like `[::1]:1234`, and a leading `tcp://` or trailing `/` is ignored
+pub fn parse_server_address(url: &str) -> io::Result<(&str, u16)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let address = url
+        .strip_prefix("tcp://")
+        .unwrap_or(url)
+        .trim_end_matches('/');
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| invalid(format!("Invalid port {:?} in server address {}", port, url)))
+    };
+    if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, rest) = bracketed
+            .split_once(']')
+            .ok_or_else(|| invalid(format!("Missing ] in server address {}", url)))?;
+        let port = match rest {
+            "" => DEFAULT_PORT,
+            _ => match rest.strip_prefix(':') {
+                Some(port) => parse_port(port)?,
+                None => return Err(invalid(format!("Expected :PORT after ] in {}", url))),
+            },
+        };
+        return Ok((host, port));
+    }
+    match address.matches(':').count() {
+        0 => Ok((address, DEFAULT_PORT)),
+        1 => {
+            let (host, port) = address.split_once(':').unwrap();
+            Ok((host, parse_port(port)?))
+        }
+        // Several colons are a bare IPv6 address, its port would be ambiguous
+        _ if address.parse::<std::net::Ipv6Addr>().is_ok() => Ok((address, DEFAULT_PORT)),
+        _ => Err(invalid(format!(
+            "Put IPv6 addresses in brackets to add a port, like [::1]:{}, got {}",
+            DEFAULT_PORT, url
+        ))),
+    }
+}
+
+// Try every address the server resolves to, in order, and keep the first that connects
+fn connect_to_any(url: &str) -> io::Result<TcpStream> {
+    let addresses: Vec<SocketAddr> = parse_server_address(url)?.to_socket_addrs()?.collect();
+    let mut last_error = None;
+    for address in &addresses {
+        match TcpStream::connect(address) {
+            Ok(connection) => return Ok(connection),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        None => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", url),
+        ),
+        Some(e) if addresses.len() > 1 => io::Error::new(
+            e.kind(),
+            format!(
+                "None of the {} addresses of {} connected, the last failed with: {}",
+                addresses.len(),
+                url,
+                e
+            ),
+        ),
+        Some(e) => e,
+    })
+}
+
+// Whether a failed connection attempt is worth retrying, e.g. while the server is starting up
+fn is_transient_connect_error(error: &io::Error) -> bool {
+    // Failed DNS lookups have no dedicated kind, so anything not known to be permanent is retried
+    !matches!(
+        error.kind(),
+        io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::Unsupported
+    )
+}
+
+// Connect to the server, retrying transient failures with a growing delay until retry_for is up
+pub fn connect_with_retry(url: &str, retry_for: std::time::Duration) -> io::Result<TcpStream> {
+    let started = std::time::Instant::now();
+    let mut delay = std::time::Duration::from_millis(250);
+    loop {
+        match connect_to_any(url) {
+            Ok(connection) => return Ok(connection),
+            Err(e) if is_transient_connect_error(&e) && started.elapsed() + delay < retry_for => {
+                eprintln!(
+                    "Failed to connect to {}, retrying in {:.1}s: {}",
+                    url,
+                    delay.as_secs_f32(),
+                    e
+                );
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(std::time::Duration::from_secs(5));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+
+    #[test]
+    fn moving_left_or_up_from_the_origin_wraps_around() {
+        let canvas_size = (100, 50);
+        let origin = Coordinates {
+            x: 0,
+            y: 0,
+            bounds: canvas_size,
+        };
+        let left = origin.moved(Direction::Left, 1);
+        assert_eq!((left.x, left.y), (canvas_size.0 - 1, 0));
+        let up = origin.moved(Direction::Up, 1);
+        assert_eq!((up.x, up.y), (0, canvas_size.1 - 1));
+        let up_left = origin.moved(Direction::UpLeft, 3);
+        assert_eq!((up_left.x, up_left.y), (97, 47));
+        // Further than the canvas is wide goes around more than once
+        let far = origin.moved(Direction::Left, 250);
+        assert_eq!(far.x, 50);
+        let back = left.moved(Direction::Right, 1);
+        assert_eq!((back.x, back.y), (0, 0));
+    }
+
+    #[test]
+    fn pixel_commands_match_the_formatted_command() {
+        let canvas_size = (u16::MAX, u16::MAX);
+        let coordinates = (0..=u16::MAX)
+            .step_by(7)
+            .chain([9, 10, 99, 100, 9999, 10000]);
+        for (i, x) in coordinates.chain([u16::MAX]).enumerate() {
+            let y = u16::MAX - x;
+            let rgb = color(i as u8, (i >> 3) as u8, 255 - i as u8);
+            for alpha in [None, Some(0), Some(0x0f), Some(i as u8), Some(255)] {
+                let pixel = Pixel {
+                    point: Coordinates {
+                        x,
+                        y,
+                        bounds: canvas_size,
+                    },
+                    rgb,
+                    alpha,
+                };
+                let mut sent = Vec::new();
+                pixel.write(&mut sent).unwrap();
+                let expected = match alpha {
+                    Some(alpha) => format!("PX {} {} {}{:02x}\n", x, y, rgb, alpha),
+                    None => format!("PX {} {} {}\n", x, y, rgb),
+                };
+                assert_eq!(String::from_utf8(sent).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn server_addresses_are_split_into_host_and_port() {
+        for (url, expected) in [
+            ("[::1]:1234", ("::1", 1234)),
+            ("[::1]", ("::1", DEFAULT_PORT)),
+            ("tcp://[fe80::1]:99/", ("fe80::1", 99)),
+            ("tcp://host:99/", ("host", 99)),
+            ("127.0.0.1:4321", ("127.0.0.1", 4321)),
+            ("::1", ("::1", DEFAULT_PORT)),
+            ("pixelflut", ("pixelflut", DEFAULT_PORT)),
+        ] {
+            assert_eq!(parse_server_address(url).unwrap(), expected, "{}", url);
+        }
+        for url in [
+            "[::1:1234",
+            "[::1]1234",
+            "host:port",
+            "host:70000",
+            "10.0.0.1:1234:5",
+        ] {
+            let error = parse_server_address(url).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", url);
+        }
+    }
+
+    #[test]
+    fn canvas_size_is_found_after_banners_and_prompts() {
+        let mut reply = "Welcome to the canvas\n> \n  > SIZE 320 240  \r\n".as_bytes();
+        assert_eq!(read_canvas_size(&mut reply).unwrap(), (320, 240));
+        let mut lowercase = "size 8 4\n".as_bytes();
+        assert_eq!(read_canvas_size(&mut lowercase).unwrap(), (8, 4));
+    }
+
+    #[test]
+    fn missing_or_empty_canvas_sizes_are_errors() {
+        let mut closed = "Welcome\n".as_bytes();
+        let error = read_canvas_size(&mut closed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Gives up after MAX_CANVAS_SIZE_LINES other lines, even if the size follows
+        let chatty = "hello\n".repeat(MAX_CANVAS_SIZE_LINES) + "SIZE 320 240\n";
+        let error = read_canvas_size(&mut chatty.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        for empty in ["SIZE 0 0\n", "SIZE 0 240\n", "SIZE 320 0\n"] {
+            let error = read_canvas_size(&mut empty.as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", empty);
+        }
+    }
+
+    // A server that answers HELP with the given text and then keeps quiet until the client is gone
+    fn help_server(help: &'static str) -> TcpStream {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            assert_eq!(request, "HELP\n");
+            stream.write_all(help.as_bytes()).unwrap();
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+        });
+        TcpStream::connect(address).unwrap()
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn pixel_rate_limits_are_taken_from_the_help_text() {
+        let banner = "Welcome to pixelflut\nPX x y rrggbb\nRate limit: 10000 px/s per client\n";
+        assert_eq!(
+            pixel_rate_limit(lines(banner)),
+            Some((10000, "Rate limit: 10000 px/s per client".to_string()))
+        );
+        assert_eq!(
+            pixel_rate_limit(lines("PX x y rrggbb\nSIZE\nCanvas 1920x1080\n")),
+            None
+        );
+        for garbage in [
+            "LIMIT unlimited\n",
+            "rate: 0 px/s\n",
+            "LIMIT 99999999999 px/s\n",
+            "limit: -\nrate n/a\n",
+        ] {
+            assert_eq!(pixel_rate_limit(lines(garbage)), None, "{}", garbage);
+        }
+        // A line without a usable number doesn't hide a later one
+        assert_eq!(
+            pixel_rate_limit(lines("No limit on reads\nLIMIT 500\n")),
+            Some((500, "LIMIT 500".to_string()))
+        );
+        let server = help_server("HELP: PX, SIZE\n  LIMIT 2500  \n");
+        assert_eq!(
+            detect_pixel_rate_limit(&server),
+            Some((2500, "LIMIT 2500".to_string()))
+        );
+    }
+
+    #[test]
+    fn offset_support_is_taken_from_the_help_text() {
+        let with_offset = "Commands:\nPX x y rrggbb\nOFFSET x y: move the origin\nSIZE\n";
+        assert!(detect_offset_support(&help_server(with_offset)));
+        assert!(!detect_offset_support(&help_server(
+            "Commands:\nPX x y rrggbb\nSIZE\n"
+        )));
+        assert!(!detect_offset_support(&help_server("")));
+    }
+
+    // An address nothing listens on
+    fn closed_port() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn refused_connections_and_failed_lookups_are_transient() {
+        let refused = connect_to_any(&closed_port()).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(is_transient_connect_error(&refused));
+        // What a failed DNS lookup looks like, it has no kind of its own
+        let lookup = io::Error::other("failed to lookup address information");
+        assert!(is_transient_connect_error(&lookup));
+    }
+
+    #[test]
+    fn invalid_addresses_are_permanent() {
+        for url in ["pixelflut:port", "[::1", "10.0.0.1:1234:5"] {
+            let error = connect_to_any(url).unwrap_err();
+            assert!(!is_transient_connect_error(&error), "{}", url);
+        }
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!is_transient_connect_error(&denied));
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let started = std::time::Instant::now();
+        let retry_for = std::time::Duration::from_secs(10);
+        let error = connect_with_retry("pixelflut:port", retry_for).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+        // Refused connections are retried until the time is up
+        let retry_for = std::time::Duration::from_millis(600);
+        let error = connect_with_retry(&closed_port(), retry_for).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(250));
+    }
+
+    #[test]
+    fn dark_colors_are_brightened_to_the_floor() {
+        for dark in [
+            color(40, 10, 10),
+            color(0, 0, 60),
+            color(0, 0, 0),
+            color(200, 0, 200),
+        ] {
+            let brightened = dark.brightened(120);
+            assert!(
+                brightened.luminance() >= 120,
+                "{:?} -> {:?}",
+                dark,
+                brightened
+            );
+            // Not much past it, the color is raised to the floor and no further
+            assert!(
+                brightened.luminance() <= 122,
+                "{:?} -> {:?}",
+                dark,
+                brightened
+            );
+        }
+        // Scaling keeps the hue while no channel saturates
+        assert_eq!(color(40, 20, 0).brightened(60), color(104, 52, 0));
+    }
+
+    #[test]
+    fn bright_colors_are_left_unchanged() {
+        for bright in [
+            color(255, 255, 0),
+            color(120, 120, 120),
+            color(255, 255, 255),
+        ] {
+            assert_eq!(bright.brightened(120), bright);
+        }
+    }
+
+    #[test]
+    fn grayscale_uses_the_luminance_of_each_channel() {
+        assert_eq!(color(255, 0, 0).grayscale(), color(76, 76, 76));
+        assert_eq!(color(0, 255, 0).grayscale(), color(149, 149, 149));
+        assert_eq!(color(0, 0, 255).grayscale(), color(29, 29, 29));
+        assert_eq!(color(255, 255, 255).grayscale(), color(255, 255, 255));
+        assert_eq!(color(0, 0, 0).grayscale(), color(0, 0, 0));
+    }
+}
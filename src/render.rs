@@ -0,0 +1,759 @@
+//! Turning sprite frames into pixel commands
+
+use crate::protocol::{Color, Coordinates, Pixel};
+use crate::sprite::{cleared_frame, Direction};
+use image::Rgba;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::sync::Mutex;
+
+/// Knobs that change how a frame is turned into pixel commands
+pub struct RenderOptions {
+    // Flush the buffer after this many rows so large sprites paint progressively
+    pub flush_rows: Option<u32>,
+    // Resend dark pixels less often than bright ones
+    pub brightness_priority: bool,
+    // Only send every nth pixel, shifting the subset each pass so all pixels get covered
+    pub every_nth_pixel: u64,
+    // Outline the sprite's bounding box in this color
+    pub debug_bbox: Option<Color>,
+    // Tint applied to every sprite pixel
+    pub tint: Option<Color>,
+    // Retry single pixels that fail with a transient error instead of failing the frame
+    pub retry_pixels: bool,
+    // Fail on pixels that land off the canvas instead of quietly clipping them
+    pub strict_bounds: bool,
+    // Pixels with an alpha at or below this are left out, so the canvas shows through
+    pub alpha_threshold: u8,
+    // Send the alpha of translucent pixels so the server blends them with the canvas
+    pub blend_alpha: bool,
+    // How far sprite pixels are brightened towards white, from 0 to 1
+    pub flash: f32,
+    // Move the server's origin to the frame with `OFFSET` and send sprite-local coordinates
+    pub use_offset: bool,
+}
+
+impl RenderOptions {
+    fn sprite_color(&self, color: Rgba<u8>) -> Color {
+        let color = Color::from(color);
+        let color = self.tint.map_or(color, |tint| color.tinted(tint));
+        if self.flash > 0.0 {
+            let white = Color {
+                r: 255,
+                g: 255,
+                b: 255,
+            };
+            // Scaled by the pixel's own brightness so a black background stays black
+            let brightness = color.r.max(color.g).max(color.b) as f32 / 255.0;
+            color.blended(white, self.flash * brightness)
+        } else {
+            color
+        }
+    }
+}
+
+// How far sprite pixels are brightened the given time after a turn, fading from full to none
+// over the flash duration
+pub fn turn_flash(since_turn_ms: u128, flash_duration_ms: u128) -> f32 {
+    1.0 - (since_turn_ms as f32 / flash_duration_ms.max(1) as f32).min(1.0)
+}
+
+// Darkest pixels are only sent every this many passes when prioritizing brightness
+pub(crate) const MAX_BRIGHTNESS_PERIOD: u64 = 4;
+
+// Number of passes between two sends of a pixel, shorter for brighter pixels
+fn brightness_period(color: Color) -> u64 {
+    1 + (255 - color.luminance() as u64) * (MAX_BRIGHTNESS_PERIOD - 1) / 255
+}
+
+// Pixel indices of a frame sorted so all pixels of one color are sent together
+pub fn color_grouped_order(frame: &image::RgbaImage) -> Vec<u32> {
+    let mut order = (0..frame.width() * frame.height()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| {
+        let pixel = frame.get_pixel(index % frame.width(), index / frame.width());
+        (pixel[0], pixel[1], pixel[2])
+    });
+    order
+}
+
+// Pixel indices of a frame in the order a Hilbert curve visits them, walking the curve over the
+// smallest power-of-two square covering the frame and skipping points outside of it
+pub fn hilbert_order(width: u32, height: u32) -> Vec<u32> {
+    let side = width.max(height).next_power_of_two();
+    let mut order = Vec::with_capacity((width * height) as usize);
+    for mut distance in 0..u64::from(side) * u64::from(side) {
+        let (mut x, mut y) = (0u32, 0u32);
+        let mut step = 1;
+        while step < side {
+            let rx = ((distance / 2) & 1) as u32;
+            let ry = ((distance ^ u64::from(rx)) & 1) as u32;
+            if ry == 0 {
+                if rx == 1 {
+                    x = step - 1 - x;
+                    y = step - 1 - y;
+                }
+                std::mem::swap(&mut x, &mut y);
+            }
+            x += step * rx;
+            y += step * ry;
+            distance /= 4;
+            step *= 2;
+        }
+        if x < width && y < height {
+            order.push(y * width + x);
+        }
+    }
+    order
+}
+
+/// Send a frame's pixels, row by row or following `order` (pixel indices in row-major numbering)
+pub fn write_frame_to_stream<T: Write>(
+    frame: &image::RgbaImage,
+    position: Coordinates,
+    buffer: &mut T,
+    canvas_size: (u16, u16),
+    options: &RenderOptions,
+    pass: u64,
+    order: Option<&[u32]>,
+) -> io::Result<()> {
+    let width = frame.width();
+    // With an offset the pixels are relative to the frame, and the canvas left to the right and
+    // below it is what they must stay within. Pixels hanging over the edge are clipped, not wrapped
+    let origin = position.wrapped();
+    if options.use_offset {
+        writeln!(buffer, "OFFSET {} {}", origin.x, origin.y)?;
+    }
+    let point = |x: u32, y: u32| {
+        let local = Coordinates {
+            x: x as u16,
+            y: y as u16,
+            bounds: canvas_size,
+        };
+        if options.use_offset {
+            let bounds = (canvas_size.0 - origin.x, canvas_size.1 - origin.y);
+            Coordinates { bounds, ..local }
+        } else {
+            local + position
+        }
+    };
+    let pixel_indices: Box<dyn Iterator<Item = u32>> = match order {
+        Some(order) => Box::new(order.iter().copied()),
+        None => Box::new(0..width * frame.height()),
+    };
+    for (index, pixel_index) in pixel_indices.enumerate() {
+        let (x, y) = (pixel_index % width, pixel_index / width);
+        let color = *frame.get_pixel(x, y);
+        let decimated = !(index as u64 + pass).is_multiple_of(options.every_nth_pixel);
+        let deprioritized =
+            options.brightness_priority && !pass.is_multiple_of(brightness_period(color.into()));
+        let transparent = color[3] <= options.alpha_threshold;
+
+        if !decimated && !deprioritized && !transparent {
+            let pixel = Pixel {
+                point: point(x, y),
+                rgb: options.sprite_color(color),
+                alpha: (options.blend_alpha && color[3] < 255).then_some(color[3]),
+            };
+            write_pixel(&pixel, buffer, options, "Sprite")?;
+        }
+
+        if let Some(rows) = options.flush_rows {
+            if (index as u32 + 1).is_multiple_of(width * rows) {
+                buffer.flush()?;
+            }
+        }
+    }
+
+    // Outline the frame on top of the sprite
+    if let Some(color) = options.debug_bbox {
+        let (width, height) = frame.dimensions();
+        let top_bottom = (0..width).flat_map(|x| [(x, 0), (x, height - 1)]);
+        let left_right = (0..height).flat_map(|y| [(0, y), (width - 1, y)]);
+        for (x, y) in top_bottom.chain(left_right) {
+            let pixel = Pixel {
+                point: point(x, y),
+                rgb: color,
+                alpha: None,
+            };
+            write_pixel(&pixel, buffer, options, "Bounding box")?;
+        }
+    }
+
+    // Everything else is still sent in canvas coordinates
+    if options.use_offset {
+        buffer.write_all(b"OFFSET 0 0\n")?;
+    }
+    Ok(())
+}
+
+// How often a pixel is retried with --pixel-retry before its error is passed on
+const PIXEL_WRITE_RETRIES: u32 = 3;
+
+// Errors after which the connection is still usable, anything else means it is gone
+fn is_transient_write_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn out_of_bounds_error(source: &str, x: u16, y: u16, canvas_size: (u16, u16)) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} pixel at {},{} is outside the {}x{} canvas",
+            source, x, y, canvas_size.0, canvas_size.1
+        ),
+    )
+}
+
+// Write a pixel unless it is off the canvas, the source names what produced it for diagnostics
+pub fn write_pixel<T: Write>(
+    pixel: &Pixel,
+    buffer: &mut T,
+    options: &RenderOptions,
+    source: &str,
+) -> io::Result<()> {
+    let (width, height) = pixel.point.bounds;
+    if pixel.point.x >= width || pixel.point.y >= height {
+        if options.strict_bounds {
+            return Err(out_of_bounds_error(
+                source,
+                pixel.point.x,
+                pixel.point.y,
+                pixel.point.bounds,
+            ));
+        }
+        return Ok(());
+    }
+
+    let mut attempt = 0;
+    loop {
+        match pixel.write(buffer) {
+            Err(e)
+                if options.retry_pixels
+                    && attempt < PIXEL_WRITE_RETRIES
+                    && is_transient_write_error(&e) =>
+            {
+                attempt += 1;
+                std::thread::sleep(std::time::Duration::from_millis(attempt as u64));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A frame as it was drawn on the canvas, kept to compose previews and snapshots and to erase it
+#[derive(Clone)]
+pub struct DrawnFrame {
+    pub frame: image::RgbaImage,
+    pub position: Coordinates,
+    pub tint: Option<Color>,
+}
+
+/// Compose the drawn frames into a canvas sized image as the server would show them. Pixels
+/// at or below the alpha threshold are left out, translucent ones are blended with `--alpha`
+pub fn render_scene(
+    drawn: &[DrawnFrame],
+    canvas_size: (u16, u16),
+    alpha_threshold: u8,
+    blend_alpha: bool,
+) -> image::RgbaImage {
+    let mut scene = image::RgbaImage::from_pixel(
+        canvas_size.0 as u32,
+        canvas_size.1 as u32,
+        Rgba([0, 0, 0, 255]),
+    );
+    for drawn in drawn {
+        for (x, y, &color) in drawn.frame.enumerate_pixels() {
+            if color[3] <= alpha_threshold {
+                continue;
+            }
+            let point = Coordinates {
+                x: x as u16,
+                y: y as u16,
+                bounds: canvas_size,
+            } + drawn.position;
+            let rgb = Color::from(color);
+            let mut rgb = drawn.tint.map_or(rgb, |tint| rgb.tinted(tint));
+            if blend_alpha && color[3] < 255 {
+                let below = Color::from(*scene.get_pixel(point.x as u32, point.y as u32));
+                rgb = below.blended(rgb, color[3] as f32 / 255.0);
+            }
+            scene.put_pixel(
+                point.x as u32,
+                point.y as u32,
+                Rgba([rgb.r, rgb.g, rgb.b, 255]),
+            );
+        }
+    }
+    scene
+}
+
+/// Recently left sprite footprints, painted in a per direction color that fades out
+pub struct DirectionTrail {
+    length: usize,
+    // Trail colors for right, left, up and down movement
+    colors: [Color; 4],
+    footprints: VecDeque<(Coordinates, Direction)>,
+}
+
+impl DirectionTrail {
+    pub fn new(length: usize, colors: [Color; 4]) -> Self {
+        DirectionTrail {
+            length,
+            colors,
+            footprints: VecDeque::with_capacity(length + 1),
+        }
+    }
+
+    pub fn record(&mut self, origin: Coordinates, direction: Direction) {
+        self.footprints.push_front((origin, direction));
+        self.footprints.truncate(self.length + 1);
+    }
+
+    // Paint the uncovered part of older footprints, oldest first so newer ones stay on top
+    pub fn write<T: Write>(
+        &self,
+        current: Coordinates,
+        size: (u32, u32),
+        buffer: &mut T,
+        options: &RenderOptions,
+    ) -> io::Result<()> {
+        for (age, &(origin, direction)) in self.footprints.iter().enumerate().skip(1).rev() {
+            let color = self.colors[direction.facing() as usize]
+                .scaled((self.length - age.min(self.length)) as f32 / self.length as f32);
+            for y in 0..size.1 as u16 {
+                for x in 0..size.0 as u16 {
+                    let point = Coordinates {
+                        x,
+                        y,
+                        bounds: origin.bounds,
+                    } + origin;
+                    if current.box_contains(size, point.x, point.y) {
+                        continue;
+                    }
+                    let pixel = Pixel {
+                        point,
+                        rgb: color,
+                        alpha: None,
+                    };
+                    write_pixel(&pixel, buffer, options, "Trail")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The visible pixels of a frame that a readback sample covers, as they should be on the canvas.
+// The sample indices wrap around the frame.
+pub fn readback_sample(
+    frame: &image::RgbaImage,
+    position: Coordinates,
+    canvas_size: (u16, u16),
+    options: &RenderOptions,
+    sample: std::ops::Range<usize>,
+) -> Vec<Pixel> {
+    let pixel_count = (frame.width() * frame.height()) as usize;
+    sample
+        .take(pixel_count)
+        .filter_map(|index| {
+            let index = index % pixel_count;
+            let (x, y) = (index as u32 % frame.width(), index as u32 / frame.width());
+            // Transparent pixels are never drawn, so whatever is there is fine, and blended ones
+            // can't be told apart from the canvas below them
+            let alpha = frame.get_pixel(x, y)[3];
+            if alpha <= options.alpha_threshold || (options.blend_alpha && alpha < 255) {
+                return None;
+            }
+            Some(Pixel {
+                point: Coordinates {
+                    x: x as u16,
+                    y: y as u16,
+                    bounds: canvas_size,
+                } + position,
+                rgb: options.sprite_color(*frame.get_pixel(x, y)),
+                alpha: None,
+            })
+        })
+        .collect()
+}
+
+// Ask the server for the color of every pixel at once and collect the answers in order
+pub fn read_canvas_colors<T: Write, R: BufRead>(
+    buffer: &mut T,
+    reader: &mut R,
+    pixels: &[Pixel],
+) -> io::Result<Vec<Option<Color>>> {
+    for pixel in pixels {
+        writeln!(buffer, "PX {} {}", pixel.point.x, pixel.point.y)?;
+    }
+    buffer.flush()?;
+
+    // Once the server didn't answer in time the remaining pixels are unknown too
+    let mut timed_out = false;
+    pixels
+        .iter()
+        .map(|_| {
+            if timed_out {
+                return Ok(None);
+            }
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    timed_out = true;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+            Ok(line
+                .split_whitespace()
+                .nth(3)
+                .and_then(|color| color.get(..6))
+                .and_then(|color| color.parse::<Color>().ok()))
+        })
+        .collect()
+}
+
+// How long a readback waits for the server to answer a `PX x y` read
+const READBACK_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// A reader for the answers to `PX x y` reads on the connection, giving up after a timeout
+/// so a server that doesn't answer reads can't stall the main loop
+pub fn readback_reader(
+    connection: &std::net::TcpStream,
+) -> io::Result<io::BufReader<std::net::TcpStream>> {
+    let connection = connection.try_clone()?;
+    connection.set_read_timeout(Some(READBACK_TIMEOUT))?;
+    Ok(io::BufReader::new(connection))
+}
+
+// Read back a sample of the sprite's pixels and redraw only those that were painted over.
+// Returns the number of redrawn pixels.
+pub fn redraw_altered_pixels<T: Write, R: BufRead>(
+    frame: &image::RgbaImage,
+    position: Coordinates,
+    buffer: &mut T,
+    reader: &mut R,
+    canvas_size: (u16, u16),
+    options: &RenderOptions,
+    sample: std::ops::Range<usize>,
+) -> io::Result<usize> {
+    let sample = readback_sample(frame, position, canvas_size, options, sample);
+    let canvas_colors = read_canvas_colors(buffer, reader, &sample)?;
+    let altered = sample
+        .into_iter()
+        .zip(canvas_colors)
+        .filter(|(pixel, canvas_color)| *canvas_color != Some(pixel.rgb))
+        .map(|(pixel, _)| pixel)
+        .collect::<Vec<_>>();
+    for pixel in &altered {
+        write_pixel(pixel, buffer, options, "Redrawn")?;
+    }
+    Ok(altered.len())
+}
+
+// Options for painting black over a drawn frame in a single pass. The black outline covers
+// the --debug-bbox one.
+pub fn clearing_options(options: &RenderOptions) -> RenderOptions {
+    RenderOptions {
+        brightness_priority: false,
+        every_nth_pixel: 1,
+        debug_bbox: options.debug_bbox.map(|_| Color { r: 0, g: 0, b: 0 }),
+        tint: None,
+        blend_alpha: false,
+        flash: 0.0,
+        ..*options
+    }
+}
+
+// Paint black over every pixel of the frames drawn in the last pass
+pub fn erase_last_frame<T: Write>(
+    preview: &Mutex<Vec<DrawnFrame>>,
+    buffer: &mut T,
+    canvas_size: (u16, u16),
+    options: &RenderOptions,
+) -> io::Result<()> {
+    let drawn_frames = std::mem::take(&mut *preview.lock().unwrap());
+    let clear_options = clearing_options(options);
+    for drawn in &drawn_frames {
+        write_frame_to_stream(
+            &cleared_frame(&drawn.frame),
+            drawn.position,
+            buffer,
+            canvas_size,
+            &clear_options,
+            0,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drawn(frame: image::RgbaImage, x: u16, y: u16) -> DrawnFrame {
+        DrawnFrame {
+            frame,
+            position: Coordinates {
+                x,
+                y,
+                bounds: (8, 8),
+            },
+            tint: None,
+        }
+    }
+
+    fn options() -> RenderOptions {
+        RenderOptions {
+            flush_rows: None,
+            brightness_priority: false,
+            every_nth_pixel: 1,
+            debug_bbox: None,
+            tint: None,
+            retry_pixels: false,
+            strict_bounds: false,
+            alpha_threshold: 0,
+            blend_alpha: false,
+            flash: 0.0,
+            use_offset: false,
+        }
+    }
+
+    fn pixel_at(x: u16, y: u16) -> Pixel {
+        Pixel {
+            point: Coordinates {
+                x,
+                y,
+                bounds: (8, 8),
+            },
+            rgb: Color { r: 255, g: 0, b: 0 },
+            alpha: None,
+        }
+    }
+
+    // The PX lines a pass over the frame at x,y sends
+    fn sent_lines(
+        frame: &image::RgbaImage,
+        x: u16,
+        y: u16,
+        options: &RenderOptions,
+        pass: u64,
+    ) -> Vec<String> {
+        let mut sent = Vec::new();
+        let position = pixel_at(x, y).point;
+        write_frame_to_stream(frame, position, &mut sent, (8, 8), options, pass, None).unwrap();
+        String::from_utf8(sent)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn hilbert_order_covers_every_pixel_exactly_once() {
+        for (width, height) in [(1, 1), (3, 5), (8, 8), (60, 59), (17, 2)] {
+            let mut order = hilbert_order(width, height);
+            assert_eq!(order.len(), (width * height) as usize);
+            order.sort_unstable();
+            order.dedup();
+            assert_eq!(order, (0..width * height).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn hilbert_order_steps_to_a_neighbor_on_square_frames() {
+        let order = hilbert_order(8, 8);
+        for pair in order.windows(2) {
+            let (a, b) = ((pair[0] % 8, pair[0] / 8), (pair[1] % 8, pair[1] / 8));
+            assert_eq!(a.0.abs_diff(b.0) + a.1.abs_diff(b.1), 1);
+        }
+    }
+
+    #[test]
+    fn turn_flash_fades_back_to_the_sprite_colors_over_the_flash_frames() {
+        let frame = image::RgbaImage::from_pixel(1, 1, Rgba([200, 100, 0, 255]));
+        // Three frames of 100 ms each
+        let flashed = |since_turn_ms| {
+            let options = RenderOptions {
+                flash: turn_flash(since_turn_ms, 300),
+                ..options()
+            };
+            sent_lines(&frame, 0, 0, &options, 0).remove(0)
+        };
+        assert_eq!(flashed(0), "PX 0 0 f3ddc8");
+        assert_eq!(flashed(150), "PX 0 0 dda064");
+        assert_eq!(flashed(300), "PX 0 0 c86400");
+        assert_eq!(flashed(10_000), "PX 0 0 c86400");
+        assert_eq!(turn_flash(0, 0), 1.0);
+        assert_eq!(turn_flash(1, 0), 0.0);
+    }
+
+    #[test]
+    fn transparent_corner_pixels_are_not_sent() {
+        let mut frame = image::RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255]));
+        frame.put_pixel(0, 0, Rgba([9, 9, 9, 0]));
+        frame.put_pixel(1, 1, Rgba([9, 9, 9, 100]));
+        let lines = sent_lines(&frame, 3, 3, &options(), 0);
+        assert_eq!(lines, ["PX 4 3 ff0000", "PX 3 4 ff0000", "PX 4 4 090909"]);
+        // A higher threshold leaves out translucent pixels as well
+        let options = RenderOptions {
+            alpha_threshold: 100,
+            ..options()
+        };
+        let lines = sent_lines(&frame, 3, 3, &options, 0);
+        assert_eq!(lines, ["PX 4 3 ff0000", "PX 3 4 ff0000"]);
+    }
+
+    #[test]
+    fn every_nth_pixel_covers_the_whole_frame_over_n_passes() {
+        let frame = image::RgbaImage::from_pixel(3, 3, Rgba([255, 0, 0, 255]));
+        let options = RenderOptions {
+            every_nth_pixel: 3,
+            ..options()
+        };
+        let mut covered = Vec::new();
+        for pass in 0..3 {
+            let lines = sent_lines(&frame, 0, 0, &options, pass);
+            assert_eq!(lines.len(), 3);
+            covered.extend(lines);
+        }
+        covered.sort();
+        covered.dedup();
+        assert_eq!(covered.len(), 9);
+        // The next pass starts over with the first subset
+        assert_eq!(
+            sent_lines(&frame, 0, 0, &options, 3),
+            sent_lines(&frame, 0, 0, &options, 0)
+        );
+    }
+
+    // Fails every third write with the given error and keeps whatever the others write
+    struct Intermittent {
+        written: Vec<u8>,
+        calls: u32,
+        error: io::ErrorKind,
+    }
+
+    impl Write for Intermittent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(3) {
+                return Err(self.error.into());
+            }
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_intermittently(error: io::ErrorKind, retry_pixels: bool) -> (io::Result<()>, String) {
+        let frame = image::RgbaImage::from_pixel(3, 2, Rgba([255, 0, 0, 255]));
+        let options = RenderOptions {
+            retry_pixels,
+            ..options()
+        };
+        let mut writer = Intermittent {
+            written: Vec::new(),
+            calls: 0,
+            error,
+        };
+        let position = pixel_at(0, 0).point;
+        let result =
+            write_frame_to_stream(&frame, position, &mut writer, (8, 8), &options, 0, None);
+        (result, String::from_utf8(writer.written).unwrap())
+    }
+
+    #[test]
+    fn transient_pixel_errors_are_retried_with_pixel_retry() {
+        let (result, written) = write_intermittently(io::ErrorKind::TimedOut, true);
+        result.unwrap();
+        assert_eq!(written.lines().count(), 6);
+        let (result, written) = write_intermittently(io::ErrorKind::TimedOut, false);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(written.lines().count(), 2);
+    }
+
+    #[test]
+    fn fatal_pixel_errors_are_passed_on_right_away() {
+        let (result, written) = write_intermittently(io::ErrorKind::BrokenPipe, true);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(written.lines().count(), 2);
+    }
+
+    #[test]
+    fn off_canvas_pixels_fail_in_strict_mode_and_are_clipped_otherwise() {
+        let mut options = RenderOptions {
+            strict_bounds: true,
+            ..options()
+        };
+        let mut sent = Vec::new();
+        let error = write_pixel(&pixel_at(8, 3), &mut sent, &options, "Test").unwrap_err();
+        assert!(error.to_string().contains("Test"));
+        options.strict_bounds = false;
+        write_pixel(&pixel_at(3, 8), &mut sent, &options, "Test").unwrap();
+        write_pixel(&pixel_at(7, 7), &mut sent, &options, "Test").unwrap();
+        assert_eq!(sent, b"PX 7 7 ff0000\n");
+    }
+
+    #[test]
+    fn trail_wraps_onto_the_canvas_in_strict_mode() {
+        let colors = [Color { r: 255, g: 0, b: 0 }; 4];
+        let mut trail = DirectionTrail::new(2, colors);
+        let origin = pixel_at(6, 6).point;
+        trail.record(origin, Direction::Right);
+        trail.record(pixel_at(0, 0).point, Direction::Right);
+        let options = RenderOptions {
+            strict_bounds: true,
+            ..options()
+        };
+        let mut sent = Vec::new();
+        trail
+            .write(pixel_at(0, 0).point, (3, 3), &mut sent, &options)
+            .unwrap();
+        // The old footprint covers 6..9 on both axes, which wraps to 6, 7 and 0, minus the
+        // pixel the current footprint covers
+        let sent = String::from_utf8(sent).unwrap();
+        assert_eq!(sent.lines().count(), 8);
+        assert!(sent.lines().all(|line| !line.contains(" 8 ")));
+    }
+
+    #[test]
+    fn scene_leaves_out_transparent_pixels() {
+        let mut frame = image::RgbaImage::from_pixel(3, 3, Rgba([255, 255, 255, 0]));
+        frame.put_pixel(1, 1, Rgba([255, 0, 0, 255]));
+        let scene = render_scene(&[drawn(frame, 2, 2)], (8, 8), 0, false);
+        assert_eq!(*scene.get_pixel(3, 3), Rgba([255, 0, 0, 255]));
+        assert_eq!(*scene.get_pixel(2, 2), Rgba([0, 0, 0, 255]));
+        assert_eq!(*scene.get_pixel(4, 4), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn scene_blends_translucent_pixels_only_with_alpha() {
+        let frame = image::RgbaImage::from_pixel(1, 1, Rgba([200, 100, 0, 128]));
+        let opaque = render_scene(&[drawn(frame.clone(), 0, 0)], (8, 8), 0, false);
+        assert_eq!(*opaque.get_pixel(0, 0), Rgba([200, 100, 0, 255]));
+        let blended = render_scene(&[drawn(frame, 0, 0)], (8, 8), 0, true);
+        assert_eq!(*blended.get_pixel(0, 0), Rgba([100, 50, 0, 255]));
+    }
+
+    #[test]
+    fn scene_composes_every_drawn_frame() {
+        let red = image::RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 255]));
+        let green = image::RgbaImage::from_pixel(1, 1, Rgba([0, 255, 0, 255]));
+        let scene = render_scene(&[drawn(red, 0, 0), drawn(green, 7, 7)], (8, 8), 0, false);
+        assert_eq!(*scene.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(*scene.get_pixel(7, 7), Rgba([0, 255, 0, 255]));
+    }
+}
@@ -0,0 +1,1070 @@
+//! Loading, turning and scaling the animation frames of a sprite
+
+use crate::protocol::{Color, Coordinates};
+use clap::ValueEnum;
+use image::codecs::gif::GifDecoder;
+use image::imageops::{flip_horizontal, flip_vertical, resize, rotate90};
+use image::{AnimationDecoder, Rgba};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Cursor;
+use std::str::FromStr;
+
+/// Replaces one exact sprite color with another, written as `AABBCC=112233`
+#[derive(Copy, Clone)]
+pub struct ColorRemap {
+    pub from: Color,
+    pub to: Color,
+}
+
+impl FromStr for ColorRemap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid remap '{}': expected AABBCC=112233", s))?;
+        Ok(ColorRemap {
+            from: from.parse()?,
+            to: to.parse()?,
+        })
+    }
+}
+
+/// Number of bits kept per color channel, e.g. `332` for a 3-3-2 bit display
+#[derive(Copy, Clone)]
+pub struct ColorDepth {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl ColorDepth {
+    fn mask(bits: u8) -> u8 {
+        (0xffu16 << (8 - bits)) as u8
+    }
+
+    // Drop the low bits of every channel that the display can't show
+    pub fn quantize(&self, color: Color) -> Color {
+        Color {
+            r: color.r & Self::mask(self.r),
+            g: color.g & Self::mask(self.g),
+            b: color.b & Self::mask(self.b),
+        }
+    }
+}
+
+impl FromStr for ColorDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .chars()
+            .filter(|c| !matches!(c, '-' | ',' | ':'))
+            .map(|c| c.to_digit(10).filter(|&bits| (1..=8).contains(&bits)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("Invalid color depth '{}': channels need 1 to 8 bits", s))?;
+        match digits[..] {
+            [bits] => Ok(ColorDepth {
+                r: bits as u8,
+                g: bits as u8,
+                b: bits as u8,
+            }),
+            [r, g, b] => Ok(ColorDepth {
+                r: r as u8,
+                g: g as u8,
+                b: b as u8,
+            }),
+            _ => Err(format!(
+                "Invalid color depth '{}': expected bits per channel like 332 or 565",
+                s
+            )),
+        }
+    }
+}
+
+/// Color changes applied to every sprite pixel when the frames are loaded
+pub struct ColorOptions {
+    // Exact source colors and what they are replaced with
+    pub remap: HashMap<Color, Color>,
+    pub grayscale: bool,
+    // Luminance darker pixels are brightened to
+    pub min_brightness: Option<u8>,
+    pub depth: Option<ColorDepth>,
+    // Opaque color the sprite is put on, None keeps the transparency
+    pub background: Option<Color>,
+}
+
+// Apply remapping, grayscale and color depth reduction to every sprite pixel, then put the
+// sprite on the background color
+pub fn apply_color_options(
+    frames: Vec<image::RgbaImage>,
+    options: &ColorOptions,
+) -> Vec<image::RgbaImage> {
+    frames
+        .into_iter()
+        .map(|mut frame| {
+            for pixel in frame.pixels_mut() {
+                let mut color = Color::from(*pixel);
+                if let Some(&target) = options.remap.get(&color) {
+                    color = target;
+                }
+                if options.grayscale {
+                    color = color.grayscale();
+                }
+                if let Some(floor) = options.min_brightness {
+                    color = color.brightened(floor);
+                }
+                if let Some(depth) = options.depth {
+                    color = depth.quantize(color);
+                }
+                *pixel = match options.background {
+                    Some(bg) => {
+                        let color = bg.blended(color, pixel[3] as f32 / 255.0);
+                        Rgba([color.r, color.g, color.b, 255])
+                    }
+                    None => Rgba([color.r, color.g, color.b, pixel[3]]),
+                };
+            }
+            frame
+        })
+        .collect()
+}
+
+/// A sprite animation that is compiled into the binary
+pub struct Theme {
+    pub name: &'static str,
+    pub gif: &'static [u8],
+}
+
+pub const THEMES: [Theme; 3] = [
+    Theme {
+        name: "pac",
+        gif: include_bytes!("pac.gif"),
+    },
+    Theme {
+        name: "ghost",
+        gif: include_bytes!("ghost.gif"),
+    },
+    Theme {
+        name: "ball",
+        gif: include_bytes!("ball.gif"),
+    },
+];
+
+pub fn find_theme(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|theme| theme.name == name)
+}
+
+pub fn parse_theme(name: &str) -> Result<&'static Theme, String> {
+    find_theme(name).ok_or_else(|| {
+        let names = THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>();
+        format!(
+            "Unknown theme '{}', available themes are: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Direction {
+    Right,
+    Left,
+    Up,
+    Down,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+}
+
+impl Direction {
+    // The closest of the four directions the sprite has frames for, diagonals face sideways
+    pub fn facing(self) -> Direction {
+        match self {
+            Direction::UpLeft | Direction::DownLeft => Direction::Left,
+            Direction::UpRight | Direction::DownRight => Direction::Right,
+            cardinal => cardinal,
+        }
+    }
+
+    // The diagonal between two perpendicular directions, e.g. up and left make up-left
+    pub fn combined(self, other: Direction) -> Option<Direction> {
+        match (self, other) {
+            (Direction::Up, Direction::Left) | (Direction::Left, Direction::Up) => {
+                Some(Direction::UpLeft)
+            }
+            (Direction::Up, Direction::Right) | (Direction::Right, Direction::Up) => {
+                Some(Direction::UpRight)
+            }
+            (Direction::Down, Direction::Left) | (Direction::Left, Direction::Down) => {
+                Some(Direction::DownLeft)
+            }
+            (Direction::Down, Direction::Right) | (Direction::Right, Direction::Down) => {
+                Some(Direction::DownRight)
+            }
+            _ => None,
+        }
+    }
+
+    // How one step in this direction changes x and y
+    pub fn steps(self) -> (i32, i32) {
+        match self {
+            Direction::Right => (1, 0),
+            Direction::Left => (-1, 0),
+            Direction::Up => (0, -1),
+            Direction::Down => (0, 1),
+            Direction::UpLeft => (-1, -1),
+            Direction::UpRight => (1, -1),
+            Direction::DownLeft => (-1, 1),
+            Direction::DownRight => (1, 1),
+        }
+    }
+
+    // The direction after bouncing off a vertical and/or horizontal wall
+    pub fn bounced(self, off_x: bool, off_y: bool) -> Direction {
+        let (x, y) = self.steps();
+        let x = if off_x { -x } else { x };
+        let y = if off_y { -y } else { y };
+        match (x, y) {
+            (1, 0) => Direction::Right,
+            (-1, 0) => Direction::Left,
+            (0, -1) => Direction::Up,
+            (-1, -1) => Direction::UpLeft,
+            (1, -1) => Direction::UpRight,
+            (-1, 1) => Direction::DownLeft,
+            (1, 1) => Direction::DownRight,
+            _ => Direction::Down,
+        }
+    }
+}
+
+/// The sprite animation for each direction Pac-Man can face
+pub struct SpriteFrames {
+    pub right: Vec<image::RgbaImage>,
+    // Precomputed left, up and down frames, `None` when they are derived on every draw instead
+    turned: Option<[Vec<image::RgbaImage>; 3]>,
+    // Tight box around the visible pixels of each right facing frame
+    opaque: Vec<Option<OpaqueBounds>>,
+}
+
+/// Part of a frame that holds all of its not fully transparent pixels
+#[derive(Copy, Clone)]
+pub struct OpaqueBounds {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl OpaqueBounds {
+    // `None` when every pixel of the frame is fully transparent
+    fn of(frame: &image::RgbaImage) -> Option<Self> {
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            if pixel[3] > 0 {
+                left = left.min(x);
+                top = top.min(y);
+                right = right.max(x);
+                bottom = bottom.max(y);
+            }
+        }
+        (left <= right).then(|| OpaqueBounds {
+            x: left,
+            y: top,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        })
+    }
+
+    // The same box after the frame was turned like `SpriteFrames::frame` turns it
+    fn turned(self, direction: Direction, frame_size: (u32, u32)) -> Self {
+        let (width, height) = frame_size;
+        match direction {
+            Direction::Right | Direction::UpRight | Direction::DownRight => self,
+            Direction::Left | Direction::UpLeft | Direction::DownLeft => OpaqueBounds {
+                x: width - self.x - self.width,
+                ..self
+            },
+            Direction::Down => OpaqueBounds {
+                x: height - self.y - self.height,
+                y: self.x,
+                width: self.height,
+                height: self.width,
+            },
+            Direction::Up => OpaqueBounds {
+                x: height - self.y - self.height,
+                y: width - self.x - self.width,
+                width: self.height,
+                height: self.width,
+            },
+        }
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+}
+
+impl SpriteFrames {
+    // Precompute the turned frames unless that would hold more than max_cached frames in memory
+    pub fn new(right: Vec<image::RgbaImage>, max_cached: Option<usize>) -> Self {
+        let turned = max_cached
+            .is_none_or(|max_cached| right.len() * 4 <= max_cached)
+            .then(|| {
+                let left = right.iter().map(flip_horizontal).collect::<Vec<_>>();
+                let down = right.iter().map(rotate90).collect::<Vec<_>>();
+                let up = down.iter().map(flip_vertical).collect::<Vec<_>>();
+                [left, up, down]
+            });
+        let opaque = right.iter().map(OpaqueBounds::of).collect();
+        SpriteFrames {
+            right,
+            turned,
+            opaque,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.right.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.right.is_empty()
+    }
+
+    pub fn frame(&self, direction: Direction, frame_idx: usize) -> Cow<'_, image::RgbaImage> {
+        let right = &self.right[frame_idx];
+        // Diagonals face sideways
+        match (&self.turned, direction) {
+            (_, Direction::Right | Direction::UpRight | Direction::DownRight) => {
+                Cow::Borrowed(right)
+            }
+            (Some([left, _, _]), Direction::Left | Direction::UpLeft | Direction::DownLeft) => {
+                Cow::Borrowed(&left[frame_idx])
+            }
+            (Some([_, up, _]), Direction::Up) => Cow::Borrowed(&up[frame_idx]),
+            (Some([_, _, down]), Direction::Down) => Cow::Borrowed(&down[frame_idx]),
+            (None, Direction::Left | Direction::UpLeft | Direction::DownLeft) => {
+                Cow::Owned(flip_horizontal(right))
+            }
+            (None, Direction::Up) => Cow::Owned(flip_vertical(&rotate90(right))),
+            (None, Direction::Down) => Cow::Owned(rotate90(right)),
+        }
+    }
+
+    pub fn opaque_bounds(&self, direction: Direction, frame_idx: usize) -> Option<OpaqueBounds> {
+        let size = self.right[frame_idx].dimensions();
+        self.opaque[frame_idx].map(|bounds| bounds.turned(direction, size))
+    }
+}
+
+// Number of precomputed sizes a pulsing sprite cycles through
+const PULSE_LEVELS: usize = 5;
+
+/// Sprite sizes for `--pulse`, from the smallest to the largest
+pub struct Pulse {
+    levels: Vec<SpriteFrames>,
+    period_ms: u128,
+}
+
+impl Pulse {
+    pub fn new(
+        sprite: &SpriteFrames,
+        amplitude: f32,
+        period_ms: u64,
+        canvas_size: (u16, u16),
+        max_cached: Option<usize>,
+        filter: ResizeFilter,
+    ) -> Self {
+        let levels = (0..PULSE_LEVELS)
+            .map(|level| {
+                let scale =
+                    1.0 - amplitude + 2.0 * amplitude * level as f32 / (PULSE_LEVELS - 1) as f32;
+                let frames = sprite
+                    .right
+                    .iter()
+                    .map(|frame| {
+                        let width = ((frame.width() as f32 * scale).round() as u32).max(1);
+                        let height = ((frame.height() as f32 * scale).round() as u32).max(1);
+                        resize(frame, width, height, filter.into())
+                    })
+                    .collect();
+                SpriteFrames::new(fit_to_canvas(frames, canvas_size, filter), max_cached)
+            })
+            .collect();
+        Pulse {
+            levels,
+            period_ms: period_ms.max(1) as u128,
+        }
+    }
+
+    // The sprite size for this point in time, growing and shrinking once per period
+    pub fn level(&self, elapsed_ms: u128) -> &SpriteFrames {
+        let phase = (elapsed_ms % self.period_ms) as f32 / self.period_ms as f32;
+        let triangle = 1.0 - (2.0 * phase - 1.0).abs();
+        &self.levels[(triangle * (PULSE_LEVELS - 1) as f32).round() as usize]
+    }
+}
+
+// Shift a frame of the given size so it stays centered on a sprite of the base size
+pub fn centered(position: Coordinates, base: (u32, u32), size: (u32, u32)) -> Coordinates {
+    let (width, height) = (position.bounds.0 as i64, position.bounds.1 as i64);
+    let dx = (base.0 as i64 - size.0 as i64) / 2;
+    let dy = (base.1 as i64 - size.1 as i64) / 2;
+    position.wrapped()
+        + Coordinates {
+            x: dx.rem_euclid(width) as u16,
+            y: dy.rem_euclid(height) as u16,
+            bounds: position.bounds,
+        }
+}
+
+// How long each frame of a sprite without timing of its own is shown
+pub const DEFAULT_FRAME_DURATION_MS: u64 = 200;
+
+/// When `--freeze-animation` holds the current frame
+#[derive(Copy, Clone, ValueEnum)]
+pub enum FreezeAnimation {
+    Moving,
+    Stationary,
+}
+
+impl FreezeAnimation {
+    // Whether the animation holds its frame, moving is whether Pac-Man stepped recently
+    pub fn freezes(self, moving: bool) -> bool {
+        match self {
+            FreezeAnimation::Moving => moving,
+            FreezeAnimation::Stationary => !moving,
+        }
+    }
+}
+
+// GIF delays shorter than this are shown for DEFAULT_GIF_DELAY_MS instead, like browsers do
+const MIN_GIF_DELAY_MS: u128 = 20;
+const DEFAULT_GIF_DELAY_MS: u128 = 100;
+
+/// When each frame of an animation ends, in milliseconds from the start of one loop
+#[derive(Clone, Default)]
+pub struct FrameTimeline {
+    // Empty when the sprite has no timing of its own
+    ends: Vec<u128>,
+}
+
+impl FrameTimeline {
+    fn from_delays(delays: impl IntoIterator<Item = u128>) -> Self {
+        let ends = delays
+            .into_iter()
+            .map(|delay| {
+                if delay < MIN_GIF_DELAY_MS {
+                    DEFAULT_GIF_DELAY_MS
+                } else {
+                    delay
+                }
+            })
+            .scan(0, |end, delay| {
+                *end += delay;
+                Some(*end)
+            })
+            .collect();
+        FrameTimeline { ends }
+    }
+
+    // The frame shown at the elapsed time, None unless the timeline covers every frame
+    fn frame_at(&self, elapsed_time: u128, frame_count: usize) -> Option<usize> {
+        let total = *self.ends.last()?;
+        (self.ends.len() == frame_count).then(|| {
+            self.ends
+                .partition_point(|&end| end <= elapsed_time % total)
+        })
+    }
+}
+
+// Pick the animation frame for the elapsed time, optionally playing the animation backwards.
+// Frames follow the sprite's own timeline if there is one, else each is shown for frame_duration
+pub fn frame_index(
+    elapsed_time: u128,
+    frame_duration: u128,
+    timeline: Option<&FrameTimeline>,
+    frame_count: usize,
+    reverse: bool,
+) -> usize {
+    let frame_idx = timeline
+        .and_then(|timeline| timeline.frame_at(elapsed_time, frame_count))
+        .unwrap_or((elapsed_time / frame_duration) as usize % frame_count);
+    if reverse {
+        frame_count - 1 - frame_idx
+    } else {
+        frame_idx
+    }
+}
+
+/// How frames are scaled to `--size`
+#[derive(Copy, Clone)]
+pub enum FrameFit {
+    // The longer side becomes the size and the shorter one keeps the proportions
+    Proportional,
+    // Scaled proportionally, then padded with transparency to a size x size square
+    Letterbox,
+    // Squashed into a size x size square
+    Stretch,
+}
+
+/// Filter used when scaling frames to `--size`
+#[derive(Copy, Clone, ValueEnum)]
+pub enum ResizeFilter {
+    // Keeps pixel art crisp
+    Nearest,
+    Triangle,
+    Catmullrom,
+    // Smoothest, suits photographic sprites
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => image::imageops::FilterType::Nearest,
+            ResizeFilter::Triangle => image::imageops::FilterType::Triangle,
+            ResizeFilter::Catmullrom => image::imageops::FilterType::CatmullRom,
+            ResizeFilter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+// Scale a frame to the sprite size. A size of 0 keeps the frame as it is
+fn fit_frame(
+    frame: &image::RgbaImage,
+    size: u32,
+    fit: FrameFit,
+    filter: ResizeFilter,
+) -> image::RgbaImage {
+    if size == 0 {
+        return frame.clone();
+    }
+    if let FrameFit::Stretch = fit {
+        return resize(frame, size, size, filter.into());
+    }
+    let (width, height) = frame.dimensions();
+    let longest_side = width.max(height);
+    let scaled_width = (width * size / longest_side).max(1);
+    let scaled_height = (height * size / longest_side).max(1);
+    let scaled = resize(frame, scaled_width, scaled_height, filter.into());
+    if let FrameFit::Proportional = fit {
+        return scaled;
+    }
+    let mut padded = image::RgbaImage::new(size, size);
+    image::imageops::overlay(
+        &mut padded,
+        &scaled,
+        ((size - scaled_width) / 2) as i64,
+        ((size - scaled_height) / 2) as i64,
+    );
+    padded
+}
+
+// Decode an animated GIF or a still image and scale its frames to the sprite size, along with
+// the GIF's frame delays
+pub fn decode_sprite(
+    bytes: &[u8],
+    size: u32,
+    fit: FrameFit,
+    filter: ResizeFilter,
+) -> image::ImageResult<(Vec<image::RgbaImage>, FrameTimeline)> {
+    if image::guess_format(bytes)? != image::ImageFormat::Gif {
+        let image = image::load_from_memory(bytes)?.into_rgba8();
+        return Ok((
+            vec![fit_frame(&image, size, fit, filter)],
+            FrameTimeline::default(),
+        ));
+    }
+    let frames = GifDecoder::new(Cursor::new(bytes))?
+        .into_frames()
+        .collect::<image::ImageResult<Vec<_>>>()?;
+    let timeline = FrameTimeline::from_delays(frames.iter().map(|frame| {
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        numerator as u128 / denominator.max(1) as u128
+    }));
+    let frames = frames
+        .iter()
+        .map(|frame| fit_frame(frame.buffer(), size, fit, filter))
+        .collect();
+    Ok((frames, timeline))
+}
+
+// Decode an embedded theme and scale its frames to the sprite size
+pub fn load_theme(
+    theme: &Theme,
+    size: u32,
+    fit: FrameFit,
+    filter: ResizeFilter,
+) -> image::ImageResult<(Vec<image::RgbaImage>, FrameTimeline)> {
+    decode_sprite(theme.gif, size, fit, filter)
+}
+
+// Largest sprite download that is accepted from --sprite
+#[cfg(feature = "http-sprite")]
+const MAX_SPRITE_DOWNLOAD_BYTES: u64 = 10 * 1024 * 1024;
+
+// Download a sprite image from an http(s) URL
+#[cfg(feature = "http-sprite")]
+pub fn fetch_sprite(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    use std::io::Read;
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(10))
+        .build();
+    let mut bytes = Vec::new();
+    agent
+        .get(url)
+        .call()?
+        .into_reader()
+        .take(MAX_SPRITE_DOWNLOAD_BYTES)
+        .read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "http-sprite"))]
+pub fn fetch_sprite(_url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("this build does not include the http-sprite feature".into())
+}
+
+// Draw a right facing Pac-Man whose mouth opens and closes by chomp_speed degrees per frame
+pub fn procedural_frames(radius: u32, color: Color, chomp_speed: f32) -> Vec<image::RgbaImage> {
+    let max_mouth_angle = 45.0_f32;
+    let steps = (max_mouth_angle / chomp_speed)
+        .ceil()
+        .clamp(1.0, max_mouth_angle) as u32;
+    let mouth_angles = (0..=steps)
+        .chain((1..steps).rev())
+        .map(|step| (step as f32 * max_mouth_angle / steps as f32).to_radians());
+
+    mouth_angles
+        .map(|mouth_angle| pacman_frame(radius * 2, color, mouth_angle))
+        .collect()
+}
+
+// Rasterize a right facing Pac-Man with a mouth wedge of mouth_angle radians above and below
+fn pacman_frame(size: u32, color: Color, mouth_angle: f32) -> image::RgbaImage {
+    let radius = size as f32 / 2.0;
+    image::RgbaImage::from_fn(size, size, |x, y| {
+        let dx = x as f32 + 0.5 - radius;
+        let dy = y as f32 + 0.5 - radius;
+        let inside = dx * dx + dy * dy <= radius * radius;
+        let in_mouth = dy.atan2(dx).abs() < mouth_angle;
+        if inside && !in_mouth {
+            Rgba([color.r, color.g, color.b, 255])
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    })
+}
+
+// Number of frames the mouth takes to open all the way when Pac-Man dies
+const DEATH_FRAME_COUNT: u32 = 10;
+
+// The classic death animation, the mouth opens wider and wider until Pac-Man is gone
+pub fn death_frames(sprite: &SpriteFrames) -> Vec<image::RgbaImage> {
+    let size = sprite.right[0].width().max(sprite.right[0].height());
+    let color = brightest_color(&sprite.right[0]).map_or(
+        Color {
+            r: 255,
+            g: 255,
+            b: 0,
+        },
+        Color::from,
+    );
+    (0..=DEATH_FRAME_COUNT)
+        .map(|step| {
+            let mouth_angle = 45.0 + 135.0 * step as f32 / DEATH_FRAME_COUNT as f32;
+            pacman_frame(size, color, mouth_angle.to_radians())
+        })
+        .collect()
+}
+
+// The canvas pixels a frame draws at position, wrapping around the canvas edges. With an
+// outline the border of the frame's box, drawn by --debug-bbox, is part of it too
+pub fn footprint(
+    frame: &image::RgbaImage,
+    position: Coordinates,
+    alpha_threshold: u8,
+    outline: bool,
+) -> HashSet<(u16, u16)> {
+    let (width, height) = frame.dimensions();
+    frame
+        .enumerate_pixels()
+        .filter(|(x, y, color)| {
+            color[3] > alpha_threshold
+                || outline && (*x == 0 || *y == 0 || *x == width - 1 || *y == height - 1)
+        })
+        .map(|(x, y, _)| {
+            let point = position
+                + Coordinates {
+                    x: x as u16,
+                    y: y as u16,
+                    bounds: position.bounds,
+                };
+            (point.x, point.y)
+        })
+        .collect()
+}
+
+// A black frame covering exactly the pixels the given frame draws, to erase it again
+pub fn cleared_frame(frame: &image::RgbaImage) -> image::RgbaImage {
+    image::RgbaImage::from_fn(frame.width(), frame.height(), |x, y| {
+        Rgba([0, 0, 0, frame.get_pixel(x, y)[3]])
+    })
+}
+
+// The most luminous color among the visible pixels of a frame
+fn brightest_color(frame: &image::RgbaImage) -> Option<Rgba<u8>> {
+    frame
+        .pixels()
+        .filter(|pixel| pixel[3] > 0)
+        .max_by_key(|&&pixel| Color::from(pixel).luminance())
+        .copied()
+}
+
+// Shrink frames that don't fit on the canvas, down to a single pixel in the sprite's brightest color
+pub fn fit_to_canvas(
+    frames: Vec<image::RgbaImage>,
+    canvas_size: (u16, u16),
+    filter: ResizeFilter,
+) -> Vec<image::RgbaImage> {
+    let limit = canvas_size.0.min(canvas_size.1).max(1) as u32;
+    frames
+        .into_iter()
+        .map(|frame| {
+            let (width, height) = frame.dimensions();
+            if width <= canvas_size.0 as u32 && height <= canvas_size.1 as u32 {
+                return frame;
+            }
+            if limit > 1 {
+                return fit_frame(&frame, limit, FrameFit::Proportional, filter);
+            }
+            let brightest = brightest_color(&frame).unwrap_or(Rgba([0, 0, 0, 0]));
+            image::RgbaImage::from_pixel(1, 1, brightest)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORANGE: Color = Color {
+        r: 0xff,
+        g: 0xb8,
+        b: 0x52,
+    };
+
+    #[test]
+    fn diagonals_face_the_nearest_side() {
+        for (direction, facing) in [
+            (Direction::Up, Direction::Up),
+            (Direction::Down, Direction::Down),
+            (Direction::Left, Direction::Left),
+            (Direction::Right, Direction::Right),
+            (Direction::UpLeft, Direction::Left),
+            (Direction::DownLeft, Direction::Left),
+            (Direction::UpRight, Direction::Right),
+            (Direction::DownRight, Direction::Right),
+        ] {
+            assert!(direction.facing() == facing);
+        }
+    }
+
+    #[test]
+    fn bouncing_reverses_the_direction_off_each_wall() {
+        for (direction, off_x, off_y, bounced) in [
+            (Direction::Right, true, false, Direction::Left),
+            (Direction::Up, false, true, Direction::Down),
+            // Hitting a wall the direction doesn't move towards changes nothing
+            (Direction::Right, false, true, Direction::Right),
+            (Direction::UpRight, true, false, Direction::UpLeft),
+            (Direction::UpRight, false, true, Direction::DownRight),
+            (Direction::DownLeft, true, true, Direction::UpRight),
+        ] {
+            assert!(direction.bounced(off_x, off_y) == bounced);
+        }
+    }
+
+    #[test]
+    fn color_depth_332_keeps_the_high_bits_of_each_channel() {
+        let depth: ColorDepth = "332".parse().unwrap();
+        let quantized = depth.quantize(ORANGE);
+        assert_eq!(
+            quantized,
+            Color {
+                r: 0xe0,
+                g: 0xa0,
+                b: 0x40
+            }
+        );
+        // Quantizing again changes nothing, the color is already at the display's depth
+        assert_eq!(depth.quantize(quantized), quantized);
+    }
+
+    #[test]
+    fn color_depth_565_and_single_digit_depths() {
+        let rgb565: ColorDepth = "5-6-5".parse().unwrap();
+        assert_eq!(
+            rgb565.quantize(ORANGE),
+            Color {
+                r: 0xf8,
+                g: 0xb8,
+                b: 0x50
+            }
+        );
+        let one_bit: ColorDepth = "1".parse().unwrap();
+        assert_eq!(
+            one_bit.quantize(ORANGE),
+            Color {
+                r: 0x80,
+                g: 0x80,
+                b: 0x00
+            }
+        );
+        let full: ColorDepth = "888".parse().unwrap();
+        assert_eq!(full.quantize(ORANGE), ORANGE);
+    }
+
+    fn no_color_changes() -> ColorOptions {
+        ColorOptions {
+            remap: HashMap::new(),
+            grayscale: false,
+            min_brightness: None,
+            depth: None,
+            background: None,
+        }
+    }
+
+    #[test]
+    fn remapping_changes_only_the_mapped_colors() {
+        let remap: ColorRemap = "ffb852=112233".parse().unwrap();
+        let options = ColorOptions {
+            remap: HashMap::from([(remap.from, remap.to)]),
+            ..no_color_changes()
+        };
+        let mut frame = image::RgbaImage::from_pixel(2, 1, Rgba([0xff, 0xb8, 0x52, 128]));
+        frame.put_pixel(1, 0, Rgba([0xff, 0xb8, 0x53, 255]));
+        let frame = &apply_color_options(vec![frame], &options)[0];
+        // The alpha of a remapped pixel is kept
+        assert_eq!(*frame.get_pixel(0, 0), Rgba([0x11, 0x22, 0x33, 128]));
+        assert_eq!(*frame.get_pixel(1, 0), Rgba([0xff, 0xb8, 0x53, 255]));
+    }
+
+    #[test]
+    fn invalid_remaps_are_rejected() {
+        for remap in ["ffb852", "ffb852=", "=112233", "ffb852-112233"] {
+            assert!(remap.parse::<ColorRemap>().is_err(), "{}", remap);
+        }
+    }
+
+    #[test]
+    fn invalid_color_depths_are_rejected() {
+        for depth in ["", "09", "3322", "abc", "339"] {
+            assert!(depth.parse::<ColorDepth>().is_err(), "{}", depth);
+        }
+    }
+
+    fn frame_sequence(timeline: Option<&FrameTimeline>, reverse: bool) -> Vec<usize> {
+        (0..8)
+            .map(|step| frame_index(step * 100, 100, timeline, 4, reverse))
+            .collect()
+    }
+
+    #[test]
+    fn animation_freezes_only_in_the_chosen_state() {
+        assert!(FreezeAnimation::Moving.freezes(true));
+        assert!(!FreezeAnimation::Moving.freezes(false));
+        assert!(FreezeAnimation::Stationary.freezes(false));
+        assert!(!FreezeAnimation::Stationary.freezes(true));
+    }
+
+    #[test]
+    fn reversed_animations_play_the_frames_backwards() {
+        assert_eq!(frame_sequence(None, false), [0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(frame_sequence(None, true), [3, 2, 1, 0, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn reversed_animations_follow_the_sprite_timeline_backwards() {
+        // The second frame is shown twice as long as the others
+        let timeline = FrameTimeline::from_delays([100, 200, 100, 100]);
+        assert_eq!(
+            frame_sequence(Some(&timeline), false),
+            [0, 1, 1, 2, 3, 0, 1, 1]
+        );
+        assert_eq!(
+            frame_sequence(Some(&timeline), true),
+            [3, 2, 2, 1, 0, 3, 2, 2]
+        );
+    }
+
+    // 2:1 frame, red on the left half and blue on the right
+    fn wide_frame() -> image::RgbaImage {
+        image::RgbaImage::from_fn(4, 2, |x, _| match x {
+            0 | 1 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        })
+    }
+
+    #[test]
+    fn letterboxed_frames_keep_the_aspect_ratio_and_pad_with_transparency() {
+        let frame = fit_frame(&wide_frame(), 8, FrameFit::Letterbox, ResizeFilter::Nearest);
+        assert_eq!(frame.dimensions(), (8, 8));
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            let expected = match (x, y) {
+                (_, 0..=1 | 6..=7) => Rgba([0, 0, 0, 0]),
+                (0..=3, _) => Rgba([255, 0, 0, 255]),
+                _ => Rgba([0, 0, 255, 255]),
+            };
+            assert_eq!(*pixel, expected, "at {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn only_stretching_distorts_the_frame() {
+        let proportional = fit_frame(
+            &wide_frame(),
+            8,
+            FrameFit::Proportional,
+            ResizeFilter::Nearest,
+        );
+        assert_eq!(proportional.dimensions(), (8, 4));
+        let stretched = fit_frame(&wide_frame(), 8, FrameFit::Stretch, ResizeFilter::Nearest);
+        assert_eq!(stretched.dimensions(), (8, 8));
+        assert_eq!(*stretched.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn sprites_shrink_to_fit_tiny_canvases_and_still_move_on_them() {
+        let (frames, _) = load_theme(
+            &THEMES[0],
+            60,
+            FrameFit::Proportional,
+            ResizeFilter::Nearest,
+        )
+        .unwrap();
+        for canvas_size in [(1, 1), (8, 8), (16, 16)] {
+            let fitted = fit_to_canvas(frames.clone(), canvas_size, ResizeFilter::Nearest);
+            assert_eq!(fitted.len(), frames.len());
+            let size = fitted[0].dimensions();
+            assert!(size.0 <= canvas_size.0 as u32 && size.1 <= canvas_size.1 as u32);
+            // Still recognizable: at least one visible pixel in every frame
+            assert!(fitted.iter().all(|frame| brightest_color(frame).is_some()));
+
+            let mut wrapping = Coordinates {
+                x: 0,
+                y: 0,
+                bounds: canvas_size,
+            };
+            let mut clamped = wrapping;
+            for direction in [
+                Direction::Left,
+                Direction::Up,
+                Direction::Right,
+                Direction::Down,
+            ] {
+                for _ in 0..20 {
+                    wrapping = wrapping.moved(direction, 3);
+                    clamped = clamped.moved_within(direction, 3, size).0;
+                    assert!(wrapping.x < canvas_size.0 && wrapping.y < canvas_size.1);
+                    assert!(clamped.x as u32 + size.0 <= canvas_size.0 as u32);
+                    assert!(clamped.y as u32 + size.1 <= canvas_size.1 as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_pixel_canvas_shows_the_brightest_sprite_color() {
+        let mut frame = image::RgbaImage::from_pixel(4, 4, Rgba([40, 40, 40, 255]));
+        frame.put_pixel(2, 1, Rgba([255, 255, 0, 255]));
+        frame.put_pixel(3, 3, Rgba([255, 255, 255, 0]));
+        let fitted = fit_to_canvas(vec![frame], (1, 1), ResizeFilter::Nearest);
+        assert_eq!(fitted[0].dimensions(), (1, 1));
+        assert_eq!(*fitted[0].get_pixel(0, 0), Rgba([255, 255, 0, 255]));
+    }
+
+    // Red and blue squares, any other color can only come from a smoothing filter
+    fn checkerboard() -> image::RgbaImage {
+        image::RgbaImage::from_fn(4, 4, |x, y| match (x + y) % 2 {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 255]),
+        })
+    }
+
+    fn is_smoothed(frame: &image::RgbaImage) -> bool {
+        frame
+            .pixels()
+            .any(|pixel| ![Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])].contains(pixel))
+    }
+
+    #[test]
+    fn pulse_and_canvas_fitting_scale_with_the_chosen_filter() {
+        let sprite = SpriteFrames::new(vec![checkerboard()], None);
+        for (filter, smoothed) in [
+            (ResizeFilter::Nearest, false),
+            (ResizeFilter::Triangle, true),
+        ] {
+            let pulse = Pulse::new(&sprite, 0.5, 1000, (100, 100), None, filter);
+            let smallest = pulse.level(0).frame(Direction::Right, 0);
+            assert_eq!(smallest.dimensions(), (2, 2));
+            assert_eq!(is_smoothed(&smallest), smoothed);
+
+            let fitted = fit_to_canvas(vec![checkerboard()], (3, 3), filter);
+            assert_eq!(fitted[0].dimensions(), (3, 3));
+            assert_eq!(is_smoothed(&fitted[0]), smoothed);
+        }
+    }
+
+    fn box_of(bounds: Option<OpaqueBounds>) -> Option<(u32, u32, u32, u32)> {
+        bounds.map(|b| (b.x, b.y, b.width, b.height))
+    }
+
+    #[test]
+    fn opaque_bounds_leave_out_transparent_margins() {
+        let mut frame = image::RgbaImage::new(6, 4);
+        frame.put_pixel(1, 1, Rgba([255, 255, 0, 255]));
+        frame.put_pixel(3, 2, Rgba([255, 255, 0, 1]));
+        let sprite = SpriteFrames::new(vec![frame, image::RgbaImage::new(6, 4)], None);
+        assert_eq!(
+            box_of(sprite.opaque_bounds(Direction::Right, 0)),
+            Some((1, 1, 3, 2))
+        );
+        // A fully transparent frame has no box at all
+        assert_eq!(box_of(sprite.opaque_bounds(Direction::Right, 1)), None);
+        // The box turns along with the frame
+        for direction in [
+            Direction::Left,
+            Direction::Up,
+            Direction::Down,
+            Direction::UpLeft,
+        ] {
+            let turned = OpaqueBounds::of(&sprite.frame(direction, 0));
+            assert_eq!(box_of(sprite.opaque_bounds(direction, 0)), box_of(turned));
+        }
+    }
+
+    #[test]
+    fn footprint_includes_the_outline_only_when_asked() {
+        let mut frame = image::RgbaImage::new(3, 3);
+        frame.put_pixel(1, 1, Rgba([255, 255, 0, 255]));
+        let position = Coordinates {
+            x: 0,
+            y: 0,
+            bounds: (10, 10),
+        };
+        assert_eq!(
+            footprint(&frame, position, 0, false),
+            HashSet::from([(1, 1)])
+        );
+        let outlined = footprint(&frame, position, 0, true);
+        assert_eq!(outlined.len(), 9);
+        assert!(outlined.contains(&(0, 0)) && outlined.contains(&(2, 2)));
+    }
+}
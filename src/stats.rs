@@ -0,0 +1,451 @@
+//! Counting and reporting what is sent
+
+use crate::protocol::Coordinates;
+use crate::render::{write_frame_to_stream, RenderOptions, MAX_BRIGHTNESS_PERIOD};
+use crate::sprite::Direction;
+use crate::transport::{BinaryPixels, CountingWriter};
+use clap::ValueEnum;
+use std::io::{self, Write};
+use std::net::TcpStream;
+
+/// Outcome of the readback checks of a stationary sprite
+#[derive(Default)]
+pub struct ReadbackStats {
+    pub checks: u64,
+    skipped: u64,
+    redrawn_pixels: u64,
+    last_report: Option<std::time::Instant>,
+}
+
+impl ReadbackStats {
+    pub fn record(&mut self, redrawn: usize) {
+        self.checks += 1;
+        if redrawn == 0 {
+            self.skipped += 1;
+        }
+        self.redrawn_pixels += redrawn as u64;
+
+        let report_due = self
+            .last_report
+            .is_none_or(|last| last.elapsed() >= std::time::Duration::from_secs(10));
+        if report_due {
+            println!(
+                "Readback: {} of {} resends skipped, {} pixels redrawn",
+                self.skipped, self.checks, self.redrawn_pixels
+            );
+            self.last_report = Some(std::time::Instant::now());
+        }
+    }
+}
+
+// How long the pixel rate reported by `GET /metrics` is averaged over
+const METRICS_RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Live numbers for `GET /metrics`, updated by the render loop after every pass
+pub struct Metrics {
+    pixels: u64,
+    pixels_per_second: f64,
+    position: (u16, u16),
+    direction: Direction,
+    window_start: std::time::Instant,
+    window_pixels: u64,
+}
+
+impl Metrics {
+    pub fn new(position: (u16, u16), direction: Direction) -> Self {
+        Metrics {
+            pixels: 0,
+            pixels_per_second: 0.0,
+            position,
+            direction,
+            window_start: std::time::Instant::now(),
+            window_pixels: 0,
+        }
+    }
+
+    // Take in the total pixels sent so far and where Pac-Man is now
+    pub fn update(&mut self, pixels: u64, position: Coordinates, direction: Direction) {
+        self.pixels = pixels;
+        self.position = (position.x, position.y);
+        self.direction = direction;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= METRICS_RATE_WINDOW {
+            self.pixels_per_second = (pixels - self.window_pixels) as f64 / elapsed.as_secs_f64();
+            self.window_start = std::time::Instant::now();
+            self.window_pixels = pixels;
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "pixels": self.pixels,
+            "pixels_per_second": self.pixels_per_second.round(),
+            "position": { "x": self.position.0, "y": self.position.1 },
+            "direction": self.direction.to_possible_value().unwrap().get_name(),
+        })
+    }
+}
+
+/// Movement that was actually achieved, since a slow connection stretches every step
+pub struct MovementStats {
+    window_start: std::time::Instant,
+    pub(crate) steps: u64,
+    pub distance: u64,
+}
+
+impl Default for MovementStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MovementStats {
+    pub fn new() -> Self {
+        MovementStats {
+            window_start: std::time::Instant::now(),
+            steps: 0,
+            distance: 0,
+        }
+    }
+
+    // Record a step and report the achieved rate every 10 seconds, against the requested one
+    // when the step rate is fixed by a tempo
+    pub fn record(&mut self, speed: u16, requested_steps_per_second: Option<f64>) {
+        self.steps += 1;
+        self.distance += speed as u64;
+
+        let elapsed = self.window_start.elapsed().as_secs_f64();
+        if elapsed < 10.0 {
+            return;
+        }
+        println!(
+            "{}",
+            self.report(elapsed, speed, requested_steps_per_second)
+        );
+        *self = MovementStats::new();
+    }
+
+    fn report(&self, elapsed: f64, speed: u16, requested_steps_per_second: Option<f64>) -> String {
+        let achieved = self.distance as f64 / elapsed;
+        match requested_steps_per_second {
+            Some(steps_per_second) => format!(
+                "Movement: {:.1} pixels per second of {:.1} requested",
+                achieved,
+                steps_per_second * speed as f64
+            ),
+            None => format!(
+                "Movement: {:.1} pixels per second in {:.1} steps per second",
+                achieved,
+                self.steps as f64 / elapsed
+            ),
+        }
+    }
+}
+
+// How often buffered telemetry rows are written to the file
+const TELEMETRY_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// CSV log with one row per drawn frame, for plotting movement and bandwidth
+pub struct Telemetry {
+    pub writer: io::BufWriter<std::fs::File>,
+    last_flush: std::time::Instant,
+}
+
+impl Telemetry {
+    // Append to the file, starting it with a header row when it is new or empty
+    pub fn open(path: &std::path::Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+        let mut writer = io::BufWriter::new(file);
+        if is_empty {
+            writeln!(writer, "timestamp_ms,x,y,direction,frame,pixels")?;
+        }
+        Ok(Telemetry {
+            writer,
+            last_flush: std::time::Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        position: Coordinates,
+        direction: Direction,
+        frame_idx: usize,
+        pixels: u64,
+    ) -> io::Result<()> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis());
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{}",
+            timestamp,
+            position.x,
+            position.y,
+            direction.to_possible_value().unwrap().get_name(),
+            frame_idx,
+            pixels
+        )?;
+        if self.last_flush.elapsed() >= TELEMETRY_FLUSH_INTERVAL {
+            self.writer.flush()?;
+            self.last_flush = std::time::Instant::now();
+        }
+        Ok(())
+    }
+}
+
+// Passes per second that --auto-optimize aims for when picking a decimation level
+pub const AUTO_OPTIMIZE_PASSES_PER_SECOND: f64 = 25.0;
+
+// Send the sprite in a short burst and measure how many pixels per second get through
+pub fn calibrate_throughput(
+    stream: &TcpStream,
+    frame: &image::RgbaImage,
+    position: Coordinates,
+    canvas_size: (u16, u16),
+    duration: std::time::Duration,
+) -> io::Result<f64> {
+    let options = RenderOptions {
+        flush_rows: None,
+        brightness_priority: false,
+        every_nth_pixel: 1,
+        debug_bbox: None,
+        tint: None,
+        retry_pixels: false,
+        strict_bounds: false,
+        alpha_threshold: 0,
+        blend_alpha: false,
+        flash: 0.0,
+        use_offset: false,
+    };
+    stream.set_write_timeout(Some(duration))?;
+    let mut writer = io::BufWriter::new(stream);
+
+    let start = std::time::Instant::now();
+    let mut pixels: u64 = 0;
+    let mut burst = || {
+        while start.elapsed() < duration {
+            write_frame_to_stream(frame, position, &mut writer, canvas_size, &options, 0, None)?;
+            pixels += frame.width() as u64 * frame.height() as u64;
+        }
+        writer.flush()
+    };
+    let result = burst();
+    let elapsed = start.elapsed().as_secs_f64();
+
+    // A timed out write may have cut a command in half, so throw away the rest and end the line
+    let (mut stream, _) = writer.into_parts();
+    stream.set_write_timeout(None)?;
+    match result {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            stream.write_all(b"\n")?;
+        }
+        result => result?,
+    }
+    Ok(pixels as f64 / elapsed)
+}
+
+// Average traffic of a pass over all frames, as estimated by --dry-run-stats
+struct DryRunStats {
+    pixels_per_pass: f64,
+    bytes_per_pass: f64,
+}
+
+impl DryRunStats {
+    // Render every frame for enough passes to cover decimation and brightness priority
+    fn measure(
+        frames: &[image::RgbaImage],
+        position: Coordinates,
+        options: &RenderOptions,
+        binary: bool,
+    ) -> io::Result<Self> {
+        let passes = options.every_nth_pixel * MAX_BRIGHTNESS_PERIOD;
+        // Bytes are taken after the encoding so --binary reports what would go over the wire
+        let mut counter = CountingWriter::new(BinaryPixels::new(Vec::new(), binary));
+        let mut bytes = 0;
+        for frame in frames {
+            for pass in 0..passes {
+                write_frame_to_stream(
+                    frame,
+                    position,
+                    &mut counter,
+                    position.bounds,
+                    options,
+                    pass,
+                    None,
+                )?;
+                bytes += counter.inner.inner.len();
+                counter.inner.inner.clear();
+            }
+        }
+        let total_passes = (frames.len() as u64 * passes) as f64;
+        Ok(DryRunStats {
+            pixels_per_pass: counter.pixels as f64 / total_passes,
+            bytes_per_pass: bytes as f64 / total_passes,
+        })
+    }
+
+    // What the traffic comes to per pass, per loop and at the pixel rate limit
+    fn report(&self, repeat: u32, max_pps: Option<u32>) -> Vec<String> {
+        let (pixels, bytes) = (self.pixels_per_pass, self.bytes_per_pass);
+        vec![
+            format!(
+                "Per pass: {:.0} pixels, {:.0} bytes ({:.1} bytes per pixel)",
+                pixels,
+                bytes,
+                bytes / pixels.max(1.0)
+            ),
+            format!(
+                "Per loop of {} passes: {:.0} pixels, {:.0} bytes",
+                repeat,
+                pixels * repeat as f64,
+                bytes * repeat as f64
+            ),
+            match max_pps {
+                Some(max_pps) => format!(
+                    "At {} pixels per second: {:.1} passes and {:.0} bytes per second",
+                    max_pps,
+                    max_pps as f64 / pixels.max(1.0),
+                    max_pps as f64 * bytes / pixels.max(1.0)
+                ),
+                None => {
+                    "Without --max-pps passes are sent as fast as the connection allows".to_string()
+                }
+            },
+        ]
+    }
+}
+
+// Report the average traffic of the frames without sending them anywhere
+pub fn print_dry_run_stats(
+    frames: &[image::RgbaImage],
+    position: Coordinates,
+    options: &RenderOptions,
+    repeat: u32,
+    max_pps: Option<u32>,
+    binary: bool,
+) -> io::Result<()> {
+    let stats = DryRunStats::measure(frames, position, options, binary)?;
+    let (width, height) = frames[0].dimensions();
+    println!(
+        "Sprite: {}x{} pixels, {} frames",
+        width,
+        height,
+        frames.len()
+    );
+    for line in stats.report(repeat, max_pps) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movement_is_reported_against_the_requested_rate() {
+        let mut stats = MovementStats::new();
+        for _ in 0..5 {
+            stats.record(3, None);
+        }
+        assert_eq!((stats.steps, stats.distance), (5, 15));
+        assert_eq!(
+            stats.report(2.0, 3, None),
+            "Movement: 7.5 pixels per second in 2.5 steps per second"
+        );
+        // At a tempo of 4 steps per second he should have made 12 pixels per second
+        assert_eq!(
+            stats.report(2.0, 3, Some(4.0)),
+            "Movement: 7.5 pixels per second of 12.0 requested"
+        );
+    }
+
+    #[test]
+    fn telemetry_starts_new_files_with_a_header() {
+        let path = std::env::temp_dir().join(format!(
+            "pixelflut-pac-{}-telemetry.csv",
+            std::process::id()
+        ));
+        let position = Coordinates {
+            x: 13,
+            y: 10,
+            bounds: (100, 50),
+        };
+        for pixels in [400, 800] {
+            let mut telemetry = Telemetry::open(&path).unwrap();
+            telemetry
+                .record(position, Direction::Right, 2, pixels)
+                .unwrap();
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        // Appending to the file again doesn't repeat the header
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "timestamp_ms,x,y,direction,frame,pixels");
+        for (line, pixels) in lines[1..].iter().zip(["400", "800"]) {
+            let fields: Vec<_> = line.split(',').collect();
+            assert!(fields[0].parse::<u128>().unwrap() > 0);
+            assert_eq!(fields[1..], ["13", "10", "right", "2", pixels]);
+        }
+    }
+
+    fn options(every_nth_pixel: u64) -> RenderOptions {
+        RenderOptions {
+            flush_rows: None,
+            brightness_priority: false,
+            every_nth_pixel,
+            debug_bbox: None,
+            tint: None,
+            retry_pixels: false,
+            strict_bounds: false,
+            alpha_threshold: 0,
+            blend_alpha: false,
+            flash: 0.0,
+            use_offset: false,
+        }
+    }
+
+    #[test]
+    fn dry_run_stats_project_the_traffic_of_a_pass() {
+        let frames = [image::RgbaImage::from_pixel(
+            2,
+            2,
+            image::Rgba([255, 0, 0, 255]),
+        )];
+        let position = Coordinates {
+            x: 10,
+            y: 20,
+            bounds: (100, 50),
+        };
+        // Every pixel is "PX 1x 2y ff0000\n"
+        let text = DryRunStats::measure(&frames, position, &options(1), false).unwrap();
+        assert_eq!((text.pixels_per_pass, text.bytes_per_pass), (4.0, 64.0));
+        assert_eq!(
+            text.report(10, Some(100)),
+            [
+                "Per pass: 4 pixels, 64 bytes (16.0 bytes per pixel)",
+                "Per loop of 10 passes: 40 pixels, 640 bytes",
+                "At 100 pixels per second: 25.0 passes and 1600 bytes per second",
+            ]
+        );
+        let binary = DryRunStats::measure(&frames, position, &options(1), true).unwrap();
+        assert_eq!(binary.bytes_per_pass, 40.0);
+        // Every other pixel per pass
+        let decimated = DryRunStats::measure(&frames, position, &options(2), false).unwrap();
+        assert_eq!(decimated.pixels_per_pass, 2.0);
+        assert_eq!(
+            decimated.report(1, None)[2],
+            "Without --max-pps passes are sent as fast as the connection allows"
+        );
+    }
+}
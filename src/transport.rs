@@ -0,0 +1,1234 @@
+//! Writer adapters that sit between the renderer and the server connections
+
+use crate::control::ErrorBackoff;
+use crate::protocol::{
+    binary_pixel, complete_commands, connect_with_retry, get_canvas_size, is_disconnect_error,
+    is_pixel_write, parse_pixel_command, Color, Coordinates,
+};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Writer adapter that waits out `WouldBlock` on non-blocking sockets instead of failing,
+/// so a frame is never cut off in the middle of a command
+pub struct RetryOnWouldBlock<W: Write> {
+    pub inner: W,
+}
+
+impl<W: Write> RetryOnWouldBlock<W> {
+    pub fn new(inner: W) -> Self {
+        RetryOnWouldBlock { inner }
+    }
+
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut W) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_micros(100));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RetryOnWouldBlock<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+/// Server connection that reconnects with a growing delay when the server drops it, so a
+/// restarted server is drawn on again from where Pac-Man is now
+pub struct Reconnecting {
+    pub inner: TcpStream,
+    pub url: String,
+    pub canvas_size: (u16, u16),
+    // A clone of the latest new connection, for the readback reader to pick up
+    pub reconnected: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl Reconnecting {
+    pub fn new(inner: TcpStream, url: &str, canvas_size: (u16, u16)) -> Self {
+        Reconnecting {
+            inner,
+            url: url.to_string(),
+            canvas_size,
+            reconnected: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn reconnect(&mut self, error: &io::Error) -> io::Result<()> {
+        eprintln!("Lost the connection to {}: {}", self.url, error);
+        let mut delay = std::time::Duration::from_millis(250);
+        let (connection, canvas_size) = loop {
+            // A server that is still shutting down may accept and then drop the connection
+            let connected = connect_with_retry(&self.url, std::time::Duration::MAX)
+                .and_then(|connection| Ok((get_canvas_size(&connection)?, connection)));
+            match connected {
+                Ok((canvas_size, connection)) => break (connection, canvas_size),
+                Err(e) if is_disconnect_error(&e) => {
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(std::time::Duration::from_secs(5));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if canvas_size != self.canvas_size {
+            eprintln!(
+                "The canvas is now {}x{} instead of {}x{}, restart to use the new size",
+                canvas_size.0, canvas_size.1, self.canvas_size.0, self.canvas_size.1
+            );
+        }
+        println!("Reconnected to {}", self.url);
+        *self.reconnected.lock().unwrap() = connection.try_clone().ok();
+        self.inner = connection;
+        Ok(())
+    }
+
+    fn retry<R>(&mut self, mut op: impl FnMut(&mut TcpStream) -> io::Result<R>) -> io::Result<R> {
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if is_disconnect_error(&e) => self.reconnect(&e)?,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for Reconnecting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+/// Token bucket that holds back writes so at most `pixels_per_second` commands reach the server
+pub struct PixelRateLimiter<W: Write> {
+    pub inner: W,
+    pixels_per_second: Option<f64>,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl<W: Write> PixelRateLimiter<W> {
+    pub fn new(inner: W, pixels_per_second: Option<u32>) -> Self {
+        let pixels_per_second = pixels_per_second.map(f64::from);
+        PixelRateLimiter {
+            inner,
+            pixels_per_second,
+            tokens: pixels_per_second.unwrap_or(0.0),
+            last_refill: std::time::Instant::now(),
+        }
+    }
+}
+
+impl<W: Write> Write for PixelRateLimiter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(rate) = self.pixels_per_second else {
+            return self.inner.write(buf);
+        };
+        let written = self.inner.write(buf)?;
+
+        // Every command is terminated by a newline, so count those as pixels
+        let now = std::time::Instant::now();
+        self.tokens = (self.tokens + (now - self.last_refill).as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+        self.tokens -= buf[..written].iter().filter(|&&b| b == b'\n').count() as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(std::time::Duration::from_secs_f64(-self.tokens / rate));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Token bucket like [`PixelRateLimiter`], but holding the bytes at or below `bytes_per_second`
+pub struct ByteRateLimiter<W: Write> {
+    pub inner: W,
+    bytes_per_second: Option<f64>,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl<W: Write> ByteRateLimiter<W> {
+    // A rate of 0 leaves the writes unthrottled
+    pub fn new(inner: W, bytes_per_second: u64) -> Self {
+        let bytes_per_second = (bytes_per_second > 0).then_some(bytes_per_second as f64);
+        ByteRateLimiter {
+            inner,
+            bytes_per_second,
+            tokens: bytes_per_second.unwrap_or(0.0),
+            last_refill: std::time::Instant::now(),
+        }
+    }
+}
+
+impl<W: Write> Write for ByteRateLimiter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(rate) = self.bytes_per_second else {
+            return self.inner.write(buf);
+        };
+        let written = self.inner.write(buf)?;
+
+        let now = std::time::Instant::now();
+        self.tokens = (self.tokens + (now - self.last_refill).as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+        self.tokens -= written as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(std::time::Duration::from_secs_f64(-self.tokens / rate));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Whether a recording keeps a command. Reads and queries like `PX x y` or `SIZE` are left out,
+// a replay never reads the answers and the server's unread replies would pile up.
+fn is_replayable(command: &[u8]) -> bool {
+    is_pixel_write(command) || command.starts_with(b"OFFSET ")
+}
+
+/// Writer adapter for `--record` that copies the drawing commands into a recording. A `# <ms>`
+/// line before the commands sent at each new millisecond since the start keeps the pace for
+/// replays
+pub struct Recorder<W: Write> {
+    pub inner: W,
+    recording: Option<Box<dyn Write>>,
+    start: std::time::Instant,
+    last_mark: Option<u128>,
+    // Incomplete command left over from the previous write
+    pending: Vec<u8>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(inner: W, recording: Option<Box<dyn Write>>) -> Self {
+        Recorder {
+            inner,
+            recording,
+            start: std::time::Instant::now(),
+            last_mark: None,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Recorder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let Some(recording) = &mut self.recording else {
+            return Ok(written);
+        };
+        let commands = complete_commands(&mut self.pending, &buf[..written]);
+        let now = self.start.elapsed().as_millis();
+        for command in commands.split(|&b| b == b'\n').filter(|c| is_replayable(c)) {
+            if self.last_mark != Some(now) {
+                writeln!(recording, "# {}", now)?;
+                self.last_mark = Some(now);
+            }
+            recording.write_all(command)?;
+            recording.write_all(b"\n")?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        if let Some(recording) = &mut self.recording {
+            recording.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn is_gzip_path(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Create a file for `--record`, gzip compressed when the path ends in `.gz`
+pub fn create_recording(path: &std::path::Path) -> io::Result<Box<dyn Write>> {
+    let file = io::BufWriter::new(std::fs::File::create(path)?);
+    Ok(if is_gzip_path(path) {
+        Box::new(flate2::write::GzEncoder::new(
+            file,
+            flate2::Compression::default(),
+        ))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Open a recording for `--replay`, decompressing it when the path ends in `.gz`
+pub fn open_recording(path: &std::path::Path) -> io::Result<Box<dyn BufRead>> {
+    let file = std::fs::File::open(path)?;
+    Ok(if is_gzip_path(path) {
+        Box::new(io::BufReader::new(flate2::read::GzDecoder::new(file)))
+    } else {
+        Box::new(io::BufReader::new(file))
+    })
+}
+
+/// Send the drawing commands of a recording at the pace they were recorded, returns how many
+/// were sent
+pub fn replay_recording<R: BufRead, W: Write>(reader: R, buffer: &mut W) -> io::Result<u64> {
+    let start = std::time::Instant::now();
+    let mut commands = 0;
+    for line in reader.split(b'\n') {
+        let line = match line {
+            Ok(line) => line,
+            // A recording cut off by a kill still replays up to where it ends. The decompressor
+            // tells a missing gzip trailer and a cut off stream apart.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidInput
+                ) =>
+            {
+                eprintln!("The recording ends early: {}", e);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let mark = line
+            .strip_prefix(b"# ")
+            .and_then(|ms| std::str::from_utf8(ms).ok()?.parse::<u64>().ok());
+        if let Some(ms) = mark {
+            let due = start + std::time::Duration::from_millis(ms);
+            let now = std::time::Instant::now();
+            if due > now {
+                buffer.flush()?;
+                std::thread::sleep(due - now);
+            }
+        } else if is_replayable(&line) {
+            buffer.write_all(&line)?;
+            buffer.write_all(b"\n")?;
+            commands += 1;
+        }
+    }
+    Ok(commands)
+}
+
+/// A pixel command a relay client sent, with the canvas pixel it sets
+pub type RelayedPixel = (u16, u16, String);
+
+/// Act as a pixelflut server for `--relay-port`: answer `SIZE` with the canvas size and pass
+/// every `PX` write on the canvas to `relayed`, one thread per client
+pub fn serve_relay(listener: TcpListener, canvas_size: (u16, u16), relayed: Sender<RelayedPixel>) {
+    let mut backoff = ErrorBackoff::new();
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => {
+                backoff.succeeded();
+                stream
+            }
+            Err(e) if backoff.failed("Accepting a relay connection", &e) => continue,
+            Err(_) => return,
+        };
+        let peer = stream.peer_addr().expect("Failed to get peer address");
+        println!("Relay client connected. (IP: {})", peer);
+        let tx_handle = relayed.clone();
+        std::thread::spawn(move || {
+            let reader = io::BufReader::new(stream.try_clone().expect("Failed to clone stream"));
+            for line in reader.lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some("SIZE"), None, None, None) => {
+                        if writeln!(stream, "SIZE {} {}", canvas_size.0, canvas_size.1).is_err() {
+                            break;
+                        }
+                    }
+                    (Some("PX"), Some(x), Some(y), Some(_)) => {
+                        let (Ok(x), Ok(y)) = (x.parse::<u16>(), y.parse::<u16>()) else {
+                            continue;
+                        };
+                        if x >= canvas_size.0 || y >= canvas_size.1 {
+                            continue;
+                        }
+                        if tx_handle.send((x, y, line)).is_err() {
+                            break;
+                        }
+                    }
+                    // Reading pixels back and other commands are not relayed
+                    _ => continue,
+                }
+            }
+            println!("Relay client disconnected! (IP: {})", peer);
+        });
+    }
+}
+
+// Send the pixels relayed since the last frame, except those Pac-Man covers anyway
+pub fn forward_relayed_pixels<W: Write>(
+    relayed: &Receiver<RelayedPixel>,
+    sprite_position: Coordinates,
+    sprite_size: (u32, u32),
+    buffer: &mut W,
+) -> io::Result<()> {
+    for (x, y, line) in relayed.try_iter() {
+        if !sprite_position.box_contains(sprite_size, x, y) {
+            writeln!(buffer, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writer adapter that copies everything sent to the server into a second, optional target
+pub struct Tee<W: Write, M: Write> {
+    pub inner: W,
+    pub mirror: Option<M>,
+}
+
+impl<W: Write, M: Write> Write for Tee<W, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(mirror) = &mut self.mirror {
+            mirror.write_all(&buf[..written])?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(mirror) = &mut self.mirror {
+            mirror.flush()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Writer adapter for `--binary` that sends pixel commands as 10 byte `PB` frames: the bytes
+/// `PB`, x and y as little endian u16 and the color as RGBA. Other commands are left as text.
+pub struct BinaryPixels<W: Write> {
+    pub inner: W,
+    enabled: bool,
+    pub pending: Vec<u8>,
+}
+
+impl<W: Write> BinaryPixels<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        BinaryPixels {
+            inner,
+            enabled,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for BinaryPixels<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.enabled {
+            return self.inner.write(buf);
+        }
+        let commands = complete_commands(&mut self.pending, buf);
+        let mut encoded = Vec::with_capacity(commands.len());
+        for command in commands.split(|&b| b == b'\n').filter(|c| !c.is_empty()) {
+            let pixel = parse_pixel_command(command).and_then(|(x, y, color)| {
+                let alpha = match color.get(6..) {
+                    Some("") => 255,
+                    Some(alpha) if alpha.len() == 2 => u8::from_str_radix(alpha, 16).ok()?,
+                    _ => return None,
+                };
+                Some(binary_pixel(x, y, color.get(..6)?.parse().ok()?, alpha))
+            });
+            match pixel {
+                Some(pixel) => encoded.extend_from_slice(&pixel),
+                None => {
+                    encoded.extend_from_slice(command);
+                    encoded.push(b'\n');
+                }
+            }
+        }
+        self.inner.write_all(&encoded)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer adapter for `--delta` that drops pixel commands repeating the color that was last
+/// sent to the same canvas pixel
+pub struct DeltaFilter<W: Write> {
+    pub inner: W,
+    pub canvas_size: (u16, u16),
+    // Last color sent to each canvas pixel row by row, tagged with its digit count, None while
+    // delta mode is off
+    sent: Option<Vec<u64>>,
+    // Incomplete command left over from the previous write
+    pub pending: Vec<u8>,
+}
+
+impl<W: Write> DeltaFilter<W> {
+    pub fn new(inner: W, canvas_size: (u16, u16), enabled: bool) -> Self {
+        let pixels = canvas_size.0 as usize * canvas_size.1 as usize;
+        DeltaFilter {
+            inner,
+            canvas_size,
+            sent: enabled.then(|| vec![u64::MAX; pixels]),
+            pending: Vec::new(),
+        }
+    }
+
+    // Take note of what the canvas actually shows at a pixel, None when that is unknown
+    pub fn record_canvas(&mut self, x: u16, y: u16, color: Option<Color>) {
+        let (width, height) = self.canvas_size;
+        if let Some(sent) = self.sent.as_mut().filter(|_| x < width && y < height) {
+            sent[y as usize * width as usize + x as usize] = color.map_or(u64::MAX, |color| {
+                ((color.r as u64) << 16 | (color.g as u64) << 8 | color.b as u64) | 6 << 32
+            });
+        }
+    }
+
+    // Send every pixel again, e.g. to a server that lost the canvas
+    pub fn forget_sent(&mut self) {
+        if let Some(sent) = &mut self.sent {
+            sent.fill(u64::MAX);
+        }
+    }
+}
+
+impl<W: Write> Write for DeltaFilter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(sent) = &mut self.sent else {
+            return self.inner.write(buf);
+        };
+        let commands = complete_commands(&mut self.pending, buf);
+        let mut changed = Vec::with_capacity(commands.len());
+        for command in commands
+            .split(|&b| b == b'\n')
+            .filter(|command| !command.is_empty())
+        {
+            let pixel = parse_pixel_command(command)
+                .filter(|&(x, y, _)| x < self.canvas_size.0 && y < self.canvas_size.1);
+            if let Some((x, y, color)) = pixel {
+                let key = u64::from_str_radix(color, 16).unwrap_or(u64::MAX - 1)
+                    | (color.len() as u64) << 32;
+                let slot = &mut sent[y as usize * self.canvas_size.0 as usize + x as usize];
+                if *slot == key {
+                    continue;
+                }
+                *slot = key;
+            }
+            changed.extend_from_slice(command);
+            changed.push(b'\n');
+        }
+        self.inner.write_all(&changed)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A connected wall of the shared canvas
+pub struct Wall {
+    pub offset: (u16, u16),
+    pub size: (u16, u16),
+    pub writer: io::BufWriter<ByteRateLimiter<RetryOnWouldBlock<TcpStream>>>,
+}
+
+/// Writer adapter that sends each pixel to the wall its position falls on, translated into
+/// that wall's coordinates, the main server covers the canvas from the origin
+pub struct WallRouter<W: Write> {
+    pub inner: W,
+    pub inner_size: (u16, u16),
+    pub walls: Vec<Wall>,
+    // Incomplete command left over from the previous write
+    pub pending: Vec<u8>,
+}
+
+impl<W: Write> WallRouter<W> {
+    fn route(&mut self, command: &[u8]) -> io::Result<()> {
+        // Everything but pixel writes, like SIZE or readback requests, is for the main server
+        let Some((x, y, color)) = parse_pixel_command(command) else {
+            self.inner.write_all(command)?;
+            return self.inner.write_all(b"\n");
+        };
+        if x < self.inner_size.0 && y < self.inner_size.1 {
+            self.inner.write_all(command)?;
+            return self.inner.write_all(b"\n");
+        }
+        let wall = self.walls.iter_mut().find(|wall| {
+            (wall.offset.0..wall.offset.0.saturating_add(wall.size.0)).contains(&x)
+                && (wall.offset.1..wall.offset.1.saturating_add(wall.size.1)).contains(&y)
+        });
+        match wall {
+            Some(wall) => writeln!(
+                wall.writer,
+                "PX {} {} {}",
+                x - wall.offset.0,
+                y - wall.offset.1,
+                color
+            ),
+            // Gaps between walls are not shown anywhere
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Write for WallRouter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.walls.is_empty() {
+            return self.inner.write(buf);
+        }
+        let commands = complete_commands(&mut self.pending, buf);
+        commands
+            .split(|&b| b == b'\n')
+            .filter(|command| !command.is_empty())
+            .try_for_each(|command| self.route(command))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for wall in &mut self.walls {
+            wall.writer.flush()?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Work for a connection pool worker
+enum PoolMessage {
+    Commands(Vec<u8>),
+    Flush,
+}
+
+/// Writer adapter for `--connections` that spreads the pixel commands over several server
+/// connections, each written by its own thread. Every canvas row always goes to the same
+/// connection. A flush is sent to every worker and waits until each of them has flushed its
+/// connection, so the main loop never moves on while a worker is still behind.
+pub struct ConnectionPool {
+    workers: Vec<std::sync::mpsc::Sender<PoolMessage>>,
+    // One reply per worker and flush, carrying the first write error since the last flush
+    flushed: std::sync::mpsc::Receiver<io::Result<()>>,
+    pub pending: Vec<u8>,
+}
+
+impl ConnectionPool {
+    pub fn new<W: Write + Send + 'static>(connections: Vec<W>) -> Self {
+        let (flushed_tx, flushed) = channel();
+        let workers = connections
+            .into_iter()
+            .map(|connection| {
+                let (worker_tx, worker_rx) = channel();
+                let flushed_tx = flushed_tx.clone();
+                std::thread::spawn(move || {
+                    let mut writer = io::BufWriter::new(connection);
+                    let mut result = Ok(());
+                    for message in worker_rx {
+                        match message {
+                            PoolMessage::Commands(commands) if result.is_ok() => {
+                                result = writer.write_all(&commands);
+                            }
+                            PoolMessage::Commands(_) => {}
+                            PoolMessage::Flush => {
+                                let flushed = std::mem::replace(&mut result, Ok(()))
+                                    .and_then(|()| writer.flush());
+                                if flushed_tx.send(flushed).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+                worker_tx
+            })
+            .collect();
+        ConnectionPool {
+            workers,
+            flushed,
+            pending: Vec::new(),
+        }
+    }
+
+    fn send(&self, worker: usize, message: PoolMessage) -> io::Result<()> {
+        self.workers[worker]
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection worker stopped"))
+    }
+}
+
+impl Write for ConnectionPool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let commands = complete_commands(&mut self.pending, buf);
+        let mut batches = vec![Vec::new(); self.workers.len()];
+        for command in commands.split(|&b| b == b'\n').filter(|c| !c.is_empty()) {
+            // Anything but a pixel, like SIZE, goes to the first connection
+            let worker =
+                parse_pixel_command(command).map_or(0, |(_, y, _)| y as usize % self.workers.len());
+            batches[worker].extend_from_slice(command);
+            batches[worker].push(b'\n');
+        }
+        for (worker, batch) in batches.into_iter().enumerate() {
+            if !batch.is_empty() {
+                self.send(worker, PoolMessage::Commands(batch))?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for worker in 0..self.workers.len() {
+            self.send(worker, PoolMessage::Flush)?;
+        }
+        let mut result = Ok(());
+        for _ in 0..self.workers.len() {
+            let flushed = self.flushed.recv().map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "connection worker stopped")
+            })?;
+            result = result.and(flushed);
+        }
+        result
+    }
+}
+
+/// A Linux framebuffer device that understands the pixelflut `PX` commands written to it
+#[cfg(all(target_os = "linux", feature = "framebuffer"))]
+pub struct Framebuffer {
+    device: std::fs::File,
+    pub width: u32,
+    pub height: u32,
+    bytes_per_pixel: usize,
+    stride: usize,
+    pub pixels: Vec<u8>,
+    // Incomplete command left over from the previous write
+    pub pending: Vec<u8>,
+    // Rows that changed since the last flush
+    dirty_rows: Option<(usize, usize)>,
+}
+
+#[cfg(all(target_os = "linux", feature = "framebuffer"))]
+impl Framebuffer {
+    pub fn open(path: &std::path::Path) -> Result<Framebuffer, Box<dyn Error>> {
+        // The geometry of /dev/fbN is published in /sys/class/graphics/fbN
+        let name = path
+            .file_name()
+            .ok_or("framebuffer path has no device name")?;
+        let sysfs = std::path::Path::new("/sys/class/graphics").join(name);
+        let read_attribute = |attribute: &str| -> Result<String, Box<dyn Error>> {
+            Ok(std::fs::read_to_string(sysfs.join(attribute))?
+                .trim()
+                .to_string())
+        };
+        let virtual_size = read_attribute("virtual_size")?;
+        let (width, height) = virtual_size
+            .split_once(',')
+            .ok_or("malformed framebuffer virtual_size")?;
+        let (width, height): (u32, u32) = (width.parse()?, height.parse()?);
+        let bytes_per_pixel = match read_attribute("bits_per_pixel")?.as_str() {
+            "16" => 2,
+            "32" => 4,
+            bits => return Err(format!("unsupported framebuffer depth of {} bits", bits).into()),
+        };
+        let stride: usize = read_attribute("stride")?.parse()?;
+
+        let device = std::fs::OpenOptions::new().write(true).open(path)?;
+        Ok(Framebuffer {
+            device,
+            width,
+            height,
+            bytes_per_pixel,
+            stride,
+            pixels: vec![0; stride * height as usize],
+            pending: Vec::new(),
+            dirty_rows: None,
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn apply_command(&mut self, command: &[u8]) {
+        let Ok(command) = std::str::from_utf8(command) else {
+            return;
+        };
+        let mut parts = command.split_whitespace();
+        let (Some("PX"), Some(x), Some(y), Some(color)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return;
+        };
+        let (Ok(x), Ok(y), Ok(color)) = (
+            x.parse::<u32>(),
+            y.parse::<u32>(),
+            color.get(..6).unwrap_or(color).parse::<Color>(),
+        ) else {
+            return;
+        };
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let offset = y as usize * self.stride + x as usize * self.bytes_per_pixel;
+        let target = &mut self.pixels[offset..offset + self.bytes_per_pixel];
+        if self.bytes_per_pixel == 2 {
+            let rgb565 = (u16::from(color.r) >> 3) << 11
+                | (u16::from(color.g) >> 2) << 5
+                | u16::from(color.b) >> 3;
+            target.copy_from_slice(&rgb565.to_ne_bytes());
+        } else {
+            target.copy_from_slice(&[color.b, color.g, color.r, 0xFF]);
+        }
+        let row = y as usize;
+        self.dirty_rows = Some(
+            self.dirty_rows
+                .map_or((row, row), |(first, last)| (first.min(row), last.max(row))),
+        );
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "framebuffer"))]
+impl Write for Framebuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let commands = complete_commands(&mut self.pending, buf);
+        for command in commands.split(|&b| b == b'\n') {
+            self.apply_command(command);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        if let Some((first, last)) = self.dirty_rows.take() {
+            let start = first * self.stride;
+            let end = (last + 1) * self.stride;
+            self.device
+                .write_all_at(&self.pixels[start..end], start as u64)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stand-in for builds without the framebuffer feature, it can never be opened
+#[cfg(not(all(target_os = "linux", feature = "framebuffer")))]
+pub enum Framebuffer {}
+
+#[cfg(not(all(target_os = "linux", feature = "framebuffer")))]
+impl Framebuffer {
+    pub fn open(_path: &std::path::Path) -> Result<Framebuffer, Box<dyn Error>> {
+        Err("this build does not include the framebuffer feature".into())
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        match *self {}
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "framebuffer")))]
+impl Write for Framebuffer {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        match *self {}
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+/// Writer adapter that counts the pixel writes and bytes passing through it, and drops every
+/// pixel write past an optional limit. Reads and other commands always pass and aren't counted
+pub struct CountingWriter<W: Write> {
+    pub inner: W,
+    pub pixels: u64,
+    pub bytes: u64,
+    pub limit: Option<u64>,
+    // Incomplete command left over from the previous write
+    pending: Vec<u8>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter {
+            inner,
+            pixels: 0,
+            bytes: 0,
+            limit: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn limit_reached(&self) -> bool {
+        self.limit.is_some_and(|limit| self.pixels >= limit)
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let commands = complete_commands(&mut self.pending, buf);
+        // Only pass on the pixel writes that still fit under the limit
+        let mut kept = Vec::with_capacity(commands.len());
+        for command in commands.split_inclusive(|&b| b == b'\n') {
+            if is_pixel_write(command) {
+                if self.limit_reached() {
+                    continue;
+                }
+                self.pixels += 1;
+            }
+            kept.extend_from_slice(command);
+        }
+        self.inner.write_all(&kept)?;
+        self.bytes += kept.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accepts a few bytes per write and fails every other call with the given error
+    struct Flaky {
+        written: Vec<u8>,
+        calls: u32,
+        error: io::ErrorKind,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 2 == 1 {
+                return Err(self.error.into());
+            }
+            let accepted = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..accepted]);
+            Ok(accepted)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flaky(error: io::ErrorKind) -> Flaky {
+        Flaky {
+            written: Vec::new(),
+            calls: 0,
+            error,
+        }
+    }
+
+    #[test]
+    fn would_block_is_waited_out_without_corrupting_commands() {
+        let mut writer = RetryOnWouldBlock::new(flaky(io::ErrorKind::WouldBlock));
+        let commands = b"PX 1 2 ff0000\nPX 3 4 00ff00\n";
+        writer.write_all(commands).unwrap();
+        assert_eq!(writer.inner.written, commands);
+    }
+
+    #[test]
+    fn other_write_errors_are_not_retried() {
+        let mut writer = RetryOnWouldBlock::new(flaky(io::ErrorKind::BrokenPipe));
+        let error = writer.write_all(b"PX 1 2 ff0000\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(writer.inner.calls, 1);
+    }
+
+    #[test]
+    fn byte_rate_limiter_holds_writes_to_the_rate() {
+        let mut limiter = ByteRateLimiter::new(Vec::new(), 2000);
+        let started = std::time::Instant::now();
+        for _ in 0..30 {
+            limiter.write_all(&[b'x'; 100]).unwrap();
+        }
+        // A second's worth of bytes goes out right away, the rest at the rate
+        assert!(started.elapsed() >= std::time::Duration::from_millis(490));
+        assert_eq!(limiter.inner.len(), 3000);
+    }
+
+    #[test]
+    fn byte_rate_limiter_with_rate_zero_never_waits() {
+        let mut limiter = ByteRateLimiter::new(Vec::new(), 0);
+        let started = std::time::Instant::now();
+        for _ in 0..1000 {
+            limiter.write_all(&[b'x'; 10_000]).unwrap();
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!(limiter.inner.len(), 10_000_000);
+    }
+
+    #[test]
+    fn counting_writer_counts_and_limits_only_pixel_writes() {
+        let mut counter = CountingWriter::new(Vec::new());
+        counter.limit = Some(2);
+        counter
+            .write_all(b"OFFSET 1 2\nPX 0 0 ff0000\nPX 1 0")
+            .unwrap();
+        counter
+            .write_all(b" 00ff00\nPX 5 5\nPX 2 0 0000ff\nSIZE\n")
+            .unwrap();
+        assert_eq!(counter.pixels, 2);
+        assert!(counter.limit_reached());
+        assert_eq!(
+            counter.inner,
+            b"OFFSET 1 2\nPX 0 0 ff0000\nPX 1 0 00ff00\nPX 5 5\nSIZE\n"
+        );
+        assert_eq!(counter.bytes, counter.inner.len() as u64);
+    }
+
+    // Render a 2x2 frame, red on top and green below, at x,y through the filter and return
+    // what passed it
+    fn render_through(filter: &mut DeltaFilter<Vec<u8>>, x: u16, y: u16) -> String {
+        let frame = image::RgbaImage::from_fn(2, 2, |_, y| match y {
+            0 => image::Rgba([255, 0, 0, 255]),
+            _ => image::Rgba([0, 255, 0, 255]),
+        });
+        let position = crate::protocol::Coordinates {
+            x,
+            y,
+            bounds: filter.canvas_size,
+        };
+        let options = crate::render::RenderOptions {
+            flush_rows: None,
+            brightness_priority: false,
+            every_nth_pixel: 1,
+            debug_bbox: None,
+            tint: None,
+            retry_pixels: false,
+            strict_bounds: false,
+            alpha_threshold: 0,
+            blend_alpha: false,
+            flash: 0.0,
+            use_offset: false,
+        };
+        crate::render::write_frame_to_stream(
+            &frame,
+            position,
+            filter,
+            filter.canvas_size,
+            &options,
+            0,
+            None,
+        )
+        .unwrap();
+        String::from_utf8(std::mem::take(&mut filter.inner)).unwrap()
+    }
+
+    #[test]
+    fn delta_filter_sends_an_unchanged_frame_only_once() {
+        let mut filter = DeltaFilter::new(Vec::new(), (8, 8), true);
+        assert_eq!(render_through(&mut filter, 2, 2).lines().count(), 4);
+        assert_eq!(render_through(&mut filter, 2, 2), "");
+        // One to the right the left column already shows the right colors, only the new one is sent
+        assert_eq!(
+            render_through(&mut filter, 3, 2),
+            "PX 4 2 ff0000\nPX 4 3 00ff00\n"
+        );
+        filter.forget_sent();
+        assert_eq!(render_through(&mut filter, 3, 2).lines().count(), 4);
+    }
+
+    #[test]
+    fn delta_filter_passes_everything_when_off() {
+        let mut filter = DeltaFilter::new(Vec::new(), (8, 8), false);
+        assert_eq!(render_through(&mut filter, 2, 2).lines().count(), 4);
+        assert_eq!(render_through(&mut filter, 2, 2).lines().count(), 4);
+    }
+
+    #[test]
+    fn binary_pixels_encodes_pixel_writes_and_keeps_other_commands() {
+        let mut binary = BinaryPixels::new(Vec::new(), true);
+        binary.write_all(b"PX 258 3 ff8001\nSIZE\nPX 1 2").unwrap();
+        binary.write_all(b" 00ff0080\nPX 4 4\n").unwrap();
+        assert_eq!(
+            binary.inner,
+            [
+                &b"PB\x02\x01\x03\x00\xff\x80\x01\xff"[..],
+                b"SIZE\n",
+                b"PB\x01\x00\x02\x00\x00\xff\x00\x80",
+                b"PX 4 4\n",
+            ]
+            .concat()
+        );
+    }
+
+    // In-memory connection for the pool that takes its time for every write, or fails them all
+    #[derive(Clone)]
+    struct PoolConnection {
+        received: Arc<Mutex<Vec<u8>>>,
+        delay: std::time::Duration,
+        broken: bool,
+    }
+
+    impl PoolConnection {
+        fn new(delay_ms: u64, broken: bool) -> Self {
+            PoolConnection {
+                received: Arc::new(Mutex::new(Vec::new())),
+                delay: std::time::Duration::from_millis(delay_ms),
+                broken,
+            }
+        }
+
+        fn received(&self) -> String {
+            String::from_utf8(self.received.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for PoolConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            std::thread::sleep(self.delay);
+            if self.broken {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.received.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pool_sends_each_row_to_the_same_connection() {
+        let connections: Vec<_> = (0..3).map(|_| PoolConnection::new(0, false)).collect();
+        let mut pool = ConnectionPool::new(connections.clone());
+        for pass in ["ff0000", "00ff00"] {
+            for y in 0..6 {
+                write!(pool, "PX 1 {} {}\nPX 2 {}", y, pass, y).unwrap();
+                writeln!(pool, " {}", pass).unwrap();
+            }
+        }
+        pool.write_all(b"SIZE\n").unwrap();
+        pool.flush().unwrap();
+
+        for (index, connection) in connections.iter().enumerate() {
+            let received = connection.received();
+            let rows: Vec<u16> = received
+                .lines()
+                .filter_map(|line| parse_pixel_command(line.as_bytes()))
+                .map(|(_, y, _)| y)
+                .collect();
+            assert_eq!(rows.len(), 8);
+            assert!(
+                rows.iter().all(|&y| y as usize % 3 == index),
+                "{}",
+                received
+            );
+            // Non-pixel commands go to the first connection
+            assert_eq!(received.ends_with("SIZE\n"), index == 0);
+        }
+    }
+
+    #[test]
+    fn pool_flush_waits_for_every_connection() {
+        let slow = PoolConnection::new(100, false);
+        let mut pool = ConnectionPool::new(vec![PoolConnection::new(0, false), slow.clone()]);
+        pool.write_all(b"PX 0 0 ff0000\nPX 0 1 ff0000\n").unwrap();
+        pool.flush().unwrap();
+        assert_eq!(slow.received(), "PX 0 1 ff0000\n");
+    }
+
+    #[test]
+    fn pool_write_errors_surface_at_the_next_flush() {
+        let working = PoolConnection::new(0, false);
+        let mut pool = ConnectionPool::new(vec![working.clone(), PoolConnection::new(0, true)]);
+        // The worker writes in the background, so the error isn't known yet
+        pool.write_all(b"PX 0 0 ff0000\nPX 0 1 ff0000\n").unwrap();
+        let error = pool.flush().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(working.received(), "PX 0 0 ff0000\n");
+    }
+
+    fn recording_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("pixelflut-pac-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn recordings_keep_only_drawing_commands_after_time_marks() {
+        let path = recording_path("marks.txt");
+        let mut recorder = Recorder::new(Vec::new(), Some(create_recording(&path).unwrap()));
+        recorder
+            .write_all(b"PX 1 2 ff0000\nPX 3 4\nSIZE\nPX 5 6 00")
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        recorder.write_all(b"ff00\nHELP\nOFFSET 1 1\n").unwrap();
+        recorder.flush().unwrap();
+        // Everything still reaches the server
+        assert_eq!(
+            recorder.inner,
+            b"PX 1 2 ff0000\nPX 3 4\nSIZE\nPX 5 6 00ff00\nHELP\nOFFSET 1 1\n"
+        );
+        drop(recorder);
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = recorded.lines().collect();
+        let mark = |line: &str| line.strip_prefix("# ").unwrap().parse::<u64>().unwrap();
+        assert_eq!(
+            [lines[1], lines[3], lines[4]],
+            ["PX 1 2 ff0000", "PX 5 6 00ff00", "OFFSET 1 1"]
+        );
+        // The command cut in two is marked at the time it was completed
+        assert!(mark(lines[2]) >= mark(lines[0]) + 20);
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn replays_keep_the_recorded_pace_and_skip_reads() {
+        let recording = b"# 0\nPX 1 1 ff0000\nPX 1 1\nSIZE\n# 100\nPX 2 2 ff0000\n";
+        let started = std::time::Instant::now();
+        let mut sent = Vec::new();
+        assert_eq!(replay_recording(&recording[..], &mut sent).unwrap(), 2);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(100));
+        assert_eq!(sent, b"PX 1 1 ff0000\nPX 2 2 ff0000\n");
+    }
+
+    // Replay the gzip recording at path after cutting it down to its first length bytes
+    fn replay_cut(path: &std::path::Path, compressed: &[u8], length: usize) -> (u64, Vec<u8>) {
+        std::fs::write(path, &compressed[..length]).unwrap();
+        let mut sent = Vec::new();
+        let replayed = replay_recording(open_recording(path).unwrap(), &mut sent).unwrap();
+        (replayed, sent)
+    }
+
+    #[test]
+    fn truncated_gzip_recordings_replay_up_to_the_cut() {
+        let path = recording_path("cut.txt.gz");
+        let mut recording = create_recording(&path).unwrap();
+        for x in 0..500 {
+            writeln!(recording, "PX {} 0 ff0000", x).unwrap();
+        }
+        // Killed right after a flush, the rest of the stream and its end never made it
+        recording.flush().unwrap();
+        let flushed = std::fs::metadata(&path).unwrap().len() as usize;
+        for x in 0..500 {
+            writeln!(recording, "PX {} 1 ff0000", x).unwrap();
+        }
+        drop(recording);
+        let compressed = std::fs::read(&path).unwrap();
+
+        let (replayed, sent) = replay_cut(&path, &compressed, flushed);
+        assert_eq!(replayed, 500);
+        assert!(sent.ends_with(b"PX 499 0 ff0000\n"));
+        // Only the checksum and length at the end are missing
+        let (replayed, sent) = replay_cut(&path, &compressed, compressed.len() - 8);
+        assert_eq!(replayed, 1000);
+        assert!(sent.ends_with(b"PX 499 1 ff0000\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,499 @@
+//! Drive the render path against a mock Pixelflut server on an ephemeral port
+
+use image::{Rgba, RgbaImage};
+use pixelflut_pac::render::{read_canvas_colors, readback_reader, readback_sample};
+use pixelflut_pac::transport::{
+    forward_relayed_pixels, serve_relay, ByteRateLimiter, DeltaFilter, Reconnecting,
+    RetryOnWouldBlock, Wall, WallRouter,
+};
+use pixelflut_pac::{
+    get_canvas_size, write_frame_to_stream, Color, Coordinates, Pixel, RenderOptions,
+};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread::JoinHandle;
+
+// Accepts a single client, answers `SIZE` with the given size and collects every `PX` and
+// `OFFSET` line until the client closes its side. Reads of pixels the canvas knows a color for are answered,
+// others are left unanswered.
+struct MockServer {
+    address: std::net::SocketAddr,
+    handle: JoinHandle<Vec<String>>,
+}
+
+impl MockServer {
+    fn start(width: u16, height: u16) -> MockServer {
+        MockServer::with_canvas(width, height, HashMap::new())
+    }
+
+    fn with_canvas(width: u16, height: u16, mut canvas: HashMap<String, String>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut received = Vec::new();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                if line == "SIZE" {
+                    writeln!(writer, "SIZE {} {}", width, height).unwrap();
+                } else if let Some(command) = line.strip_prefix("PX ") {
+                    match command.rsplit_once(' ') {
+                        Some((point, color)) if point.contains(' ') => {
+                            canvas.insert(point.to_string(), color.to_string());
+                        }
+                        _ => {
+                            if let Some(color) = canvas.get(command) {
+                                writeln!(writer, "PX {} {}", command, color).unwrap();
+                            }
+                        }
+                    }
+                    received.push(line);
+                } else if line.starts_with("OFFSET ") {
+                    received.push(line);
+                }
+            }
+            received
+        });
+        MockServer { address, handle }
+    }
+
+    fn connect(&self) -> TcpStream {
+        TcpStream::connect(self.address).unwrap()
+    }
+
+    // Close the client's side and return what the server received
+    fn finish(self, stream: TcpStream) -> Vec<String> {
+        stream.shutdown(Shutdown::Write).unwrap();
+        self.handle.join().unwrap()
+    }
+}
+
+fn options() -> RenderOptions {
+    RenderOptions {
+        flush_rows: None,
+        brightness_priority: false,
+        every_nth_pixel: 1,
+        debug_bbox: None,
+        tint: None,
+        retry_pixels: false,
+        strict_bounds: false,
+        alpha_threshold: 0,
+        blend_alpha: false,
+        flash: 0.0,
+        use_offset: false,
+    }
+}
+
+// Red, green, blue and white pixels, left to right and top to bottom
+fn two_by_two() -> RgbaImage {
+    RgbaImage::from_fn(2, 2, |x, y| match (x, y) {
+        (0, 0) => Rgba([255, 0, 0, 255]),
+        (1, 0) => Rgba([0, 255, 0, 255]),
+        (0, 1) => Rgba([0, 0, 255, 255]),
+        _ => Rgba([255, 255, 255, 255]),
+    })
+}
+
+// Render the frame at x,y on a canvas the mock reports as width x height
+fn render(frame: &RgbaImage, width: u16, height: u16, x: u16, y: u16) -> Vec<String> {
+    render_with(frame, (width, height), (x, y), &options())
+}
+
+fn render_with(
+    frame: &RgbaImage,
+    (width, height): (u16, u16),
+    (x, y): (u16, u16),
+    options: &RenderOptions,
+) -> Vec<String> {
+    let server = MockServer::start(width, height);
+    let stream = server.connect();
+    let canvas_size = get_canvas_size(&stream).unwrap();
+    assert_eq!(canvas_size, (width, height));
+
+    let position = Coordinates {
+        x,
+        y,
+        bounds: canvas_size,
+    };
+    let mut buffer = std::io::BufWriter::new(stream.try_clone().unwrap());
+    write_frame_to_stream(frame, position, &mut buffer, canvas_size, options, 0, None).unwrap();
+    buffer.flush().unwrap();
+    drop(buffer);
+    server.finish(stream)
+}
+
+#[test]
+fn canvas_size_is_read_from_the_server() {
+    let server = MockServer::start(320, 240);
+    let stream = server.connect();
+    assert_eq!(get_canvas_size(&stream).unwrap(), (320, 240));
+    assert!(server.finish(stream).is_empty());
+}
+
+#[test]
+fn frame_pixels_are_offset_by_the_position() {
+    assert_eq!(
+        render(&two_by_two(), 100, 50, 10, 20),
+        [
+            "PX 10 20 ff0000",
+            "PX 11 20 00ff00",
+            "PX 10 21 0000ff",
+            "PX 11 21 ffffff",
+        ]
+    );
+}
+
+#[test]
+fn frame_pixels_wrap_around_the_canvas_edges() {
+    assert_eq!(
+        render(&two_by_two(), 100, 50, 99, 49),
+        [
+            "PX 99 49 ff0000",
+            "PX 0 49 00ff00",
+            "PX 99 0 0000ff",
+            "PX 0 0 ffffff",
+        ]
+    );
+}
+
+#[test]
+fn offset_frames_send_sprite_local_pixels_between_offsets() {
+    let options = RenderOptions {
+        use_offset: true,
+        ..options()
+    };
+    assert_eq!(
+        render_with(&two_by_two(), (100, 50), (10, 20), &options),
+        [
+            "OFFSET 10 20",
+            "PX 0 0 ff0000",
+            "PX 1 0 00ff00",
+            "PX 0 1 0000ff",
+            "PX 1 1 ffffff",
+            "OFFSET 0 0",
+        ]
+    );
+    // Over the canvas edge the sprite is clipped instead of wrapped
+    assert_eq!(
+        render_with(&two_by_two(), (100, 50), (99, 49), &options),
+        ["OFFSET 99 49", "PX 0 0 ff0000", "OFFSET 0 0"]
+    );
+}
+
+#[test]
+fn transparent_pixels_are_not_sent() {
+    let mut frame = two_by_two();
+    frame.put_pixel(1, 0, Rgba([0, 255, 0, 0]));
+    frame.put_pixel(0, 1, Rgba([0, 0, 255, 0]));
+    assert_eq!(
+        render(&frame, 100, 50, 0, 0),
+        ["PX 0 0 ff0000", "PX 1 1 ffffff"]
+    );
+}
+
+#[test]
+fn unanswered_reads_time_out_as_unknown_colors() {
+    let server = MockServer::start(100, 50);
+    let stream = server.connect();
+    let canvas_size = get_canvas_size(&stream).unwrap();
+    let pixels: Vec<Pixel> = (0..3)
+        .map(|x| Pixel {
+            point: Coordinates {
+                x,
+                y: 0,
+                bounds: canvas_size,
+            },
+            rgb: Color { r: 0, g: 0, b: 0 },
+            alpha: None,
+        })
+        .collect();
+
+    let mut reader = readback_reader(&stream).unwrap();
+    let started = std::time::Instant::now();
+    let colors = read_canvas_colors(&mut &stream, &mut reader, &pixels).unwrap();
+    assert_eq!(colors, [None, None, None]);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(server.finish(stream), ["PX 0 0", "PX 1 0", "PX 2 0"]);
+}
+
+#[test]
+fn pixels_crossing_a_seam_are_routed_to_both_walls() {
+    // Two 4x4 walls side by side make up an 8x4 canvas
+    let (left, right) = (MockServer::start(4, 4), MockServer::start(4, 4));
+    let (left_stream, right_stream) = (left.connect(), right.connect());
+    let wall = Wall {
+        offset: (4, 0),
+        size: (4, 4),
+        writer: std::io::BufWriter::new(ByteRateLimiter::new(
+            RetryOnWouldBlock::new(right_stream.try_clone().unwrap()),
+            0,
+        )),
+    };
+    let mut router = std::io::BufWriter::new(WallRouter {
+        inner: left_stream.try_clone().unwrap(),
+        inner_size: (4, 4),
+        walls: vec![wall],
+        pending: Vec::new(),
+    });
+
+    let canvas_size = (8, 4);
+    for x in [3, 7] {
+        let position = Coordinates {
+            x,
+            y: 1,
+            bounds: canvas_size,
+        };
+        write_frame_to_stream(
+            &two_by_two(),
+            position,
+            &mut router,
+            canvas_size,
+            &options(),
+            0,
+            None,
+        )
+        .unwrap();
+    }
+    router.flush().unwrap();
+    drop(router);
+
+    // Across the seam in the middle, then wrapping around the canvas edge back onto the left wall
+    assert_eq!(
+        left.finish(left_stream),
+        [
+            "PX 3 1 ff0000",
+            "PX 3 2 0000ff",
+            "PX 0 1 00ff00",
+            "PX 0 2 ffffff",
+        ]
+    );
+    assert_eq!(
+        right.finish(right_stream),
+        [
+            "PX 0 1 00ff00",
+            "PX 0 2 ffffff",
+            "PX 3 1 ff0000",
+            "PX 3 2 0000ff",
+        ]
+    );
+}
+
+// Run the client's --probe at 1000,1000 against a mock 100x50 server. The probe is clamped onto
+// the canvas, so the pixel it reads first tells which canvas size the client went with.
+fn probed_pixel(canvas_size_flag: Option<&str>, canvas_size_env: Option<&str>) -> String {
+    let server = MockServer::start(100, 50);
+    let mut client = std::process::Command::new(env!("CARGO_BIN_EXE_pixelflut_pac"));
+    client
+        .args(["--probe", "--control-addr", "", "--web-addr", ""])
+        .args(["--url", &server.address.to_string()])
+        .env_remove("PIXELFLUT_CANVAS_SIZE")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null());
+    if let Some(size) = canvas_size_flag {
+        client.args(["--canvas-size", size]);
+    }
+    if let Some(size) = canvas_size_env {
+        client.env("PIXELFLUT_CANVAS_SIZE", size);
+    }
+    client.args(["1000", "1000"]);
+    assert!(client.status().unwrap().success());
+    server.handle.join().unwrap().remove(0)
+}
+
+#[test]
+fn canvas_size_prefers_the_flag_then_the_environment_then_the_server() {
+    let cases = [
+        (Some("30x20"), Some("60x40"), "PX 29 19"),
+        (None, Some("60x40"), "PX 59 39"),
+        (None, None, "PX 99 49"),
+    ];
+    let runs: Vec<_> = cases
+        .into_iter()
+        .map(|(flag, env, expected)| {
+            (
+                std::thread::spawn(move || probed_pixel(flag, env)),
+                expected,
+            )
+        })
+        .collect();
+    for (run, expected) in runs {
+        assert_eq!(run.join().unwrap(), expected);
+    }
+}
+
+#[test]
+fn canvas_delta_sends_only_pixels_the_canvas_does_not_show() {
+    // The sprite is intact except for its top right pixel, which someone painted over
+    let canvas = [
+        ("10 20", "ff0000"),
+        ("11 20", "000000"),
+        ("10 21", "0000ff"),
+        ("11 21", "ffffff"),
+    ]
+    .map(|(point, color)| (point.to_string(), color.to_string()));
+    let server = MockServer::with_canvas(100, 50, HashMap::from(canvas));
+    let stream = server.connect();
+    let canvas_size = get_canvas_size(&stream).unwrap();
+    let position = Coordinates {
+        x: 10,
+        y: 20,
+        bounds: canvas_size,
+    };
+    let mut buffer = std::io::BufWriter::new(DeltaFilter::new(
+        stream.try_clone().unwrap(),
+        canvas_size,
+        true,
+    ));
+    let mut reader = readback_reader(&stream).unwrap();
+
+    let frame = two_by_two();
+    let sample = readback_sample(&frame, position, canvas_size, &options(), 0..4);
+    let canvas_colors = read_canvas_colors(&mut buffer, &mut reader, &sample).unwrap();
+    for (pixel, canvas_color) in sample.iter().zip(canvas_colors) {
+        buffer
+            .get_mut()
+            .record_canvas(pixel.point.x, pixel.point.y, canvas_color);
+    }
+    // The first pass redraws only the altered pixel and the second sends nothing
+    for _ in 0..2 {
+        write_frame_to_stream(
+            &frame,
+            position,
+            &mut buffer,
+            canvas_size,
+            &options(),
+            0,
+            None,
+        )
+        .unwrap();
+    }
+    buffer.flush().unwrap();
+    drop(buffer);
+
+    assert_eq!(
+        server.finish(stream),
+        [
+            "PX 10 20",
+            "PX 11 20",
+            "PX 10 21",
+            "PX 11 21",
+            "PX 11 20 00ff00",
+        ]
+    );
+}
+
+#[test]
+fn frames_are_drawn_on_a_new_connection_after_the_server_dropped_the_old_one() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let (pixels_tx, pixels) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        // Give the first client its size, then hang up as a restarting server would
+        for restarted in [false, true] {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                if line == "SIZE" {
+                    writeln!(writer, "SIZE 100 50").unwrap();
+                    if !restarted {
+                        break;
+                    }
+                } else if line.starts_with("PX ") && pixels_tx.send(line).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let stream = TcpStream::connect(address).unwrap();
+    let canvas_size = get_canvas_size(&stream).unwrap();
+    let reconnecting = Reconnecting::new(stream, &address.to_string(), canvas_size);
+    let reconnected = reconnecting.reconnected.clone();
+    let mut buffer = std::io::BufWriter::new(reconnecting);
+    let position = Coordinates {
+        x: 10,
+        y: 20,
+        bounds: canvas_size,
+    };
+    // Frames sent before the drop is noticed are lost, the one that notices it is sent again
+    let mut first_pixel = None;
+    for _ in 0..100 {
+        write_frame_to_stream(
+            &two_by_two(),
+            position,
+            &mut buffer,
+            canvas_size,
+            &options(),
+            0,
+            None,
+        )
+        .unwrap();
+        buffer.flush().unwrap();
+        first_pixel = pixels
+            .recv_timeout(std::time::Duration::from_millis(20))
+            .ok();
+        if first_pixel.is_some() {
+            break;
+        }
+    }
+    let frame: Vec<_> = first_pixel
+        .into_iter()
+        .chain(pixels.iter().take(3))
+        .collect();
+    assert_eq!(
+        frame,
+        [
+            "PX 10 20 ff0000",
+            "PX 11 20 00ff00",
+            "PX 10 21 0000ff",
+            "PX 11 21 ffffff",
+        ]
+    );
+    assert!(reconnected.lock().unwrap().is_some());
+}
+
+#[test]
+fn relayed_pixels_reach_the_upstream_server_around_pac_man() {
+    let upstream = MockServer::start(100, 50);
+    let upstream_stream = upstream.connect();
+    let canvas_size = get_canvas_size(&upstream_stream).unwrap();
+
+    let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+    let relay_address = relay.local_addr().unwrap();
+    let (relayed_tx, relayed) = std::sync::mpsc::channel();
+    std::thread::spawn(move || serve_relay(relay, canvas_size, relayed_tx));
+
+    let mut client = TcpStream::connect(relay_address).unwrap();
+    let mut replies = BufReader::new(client.try_clone().unwrap());
+    // Commands are handled in order, so once the last SIZE is answered every pixel before it
+    // was relayed
+    client
+        .write_all(b"SIZE\nPX 1 2 ff0000\nPX 3 4\nPX 500 1 00ff00\nPX 11 11 0000ff\n")
+        .unwrap();
+    client.write_all(b"HELP\nPX 5 6 ffffff\nSIZE\n").unwrap();
+    let answers: Vec<_> = (0..2)
+        .map(|_| {
+            let mut answer = String::new();
+            replies.read_line(&mut answer).unwrap();
+            answer
+        })
+        .collect();
+    assert_eq!(answers, ["SIZE 100 50\n", "SIZE 100 50\n"]);
+
+    // Pac-Man covers 10,10 to 11,11 and is drawn over whatever is relayed there
+    let pac_man = Coordinates {
+        x: 10,
+        y: 10,
+        bounds: canvas_size,
+    };
+    let mut buffer = std::io::BufWriter::new(upstream_stream.try_clone().unwrap());
+    forward_relayed_pixels(&relayed, pac_man, (2, 2), &mut buffer).unwrap();
+    buffer.flush().unwrap();
+    drop(buffer);
+    assert_eq!(
+        upstream.finish(upstream_stream),
+        ["PX 1 2 ff0000", "PX 5 6 ffffff"]
+    );
+}